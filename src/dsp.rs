@@ -24,6 +24,44 @@ pub fn pan_to_gains(pan: f32) -> (f32, f32) {
     (theta.cos(), theta.sin())
 }
 
+/// Convert a balance value (-1..1) to left/right gain, unity at center
+#[inline]
+pub fn balance_gains(pan: f32) -> (f32, f32) {
+    let pan = pan.clamp(-1.0, 1.0);
+    ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0))
+}
+
+/// Small xorshift PRNG for audio-thread randomness (reproducible from a seed)
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    pub fn reseed(&mut self, seed: u32) {
+        self.state = seed.max(1);
+    }
+
+    #[inline]
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Uniform value in 0..1
+    #[inline]
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
+
 /// Linear interpolation between two samples
 #[inline]
 pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
@@ -106,21 +144,27 @@ impl ZdfSvf {
     }
 }
 
-/// ADSR envelope generator
+/// ADSR envelope generator, with optional delay and hold stages
 pub struct Adsr {
     sr: f32,
+    delay_samples: f32,
     a_samples: f32,
+    h_samples: f32,
     d_samples: f32,
     s_level: f32,
     r_samples: f32,
     level: f32,
+    /// Samples spent in the current timed stage (delay/hold)
+    counter: f32,
     state: AdsrState,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum AdsrState {
     Idle,
+    Delay,
     Attack,
+    Hold,
     Decay,
     Sustain,
     Release,
@@ -130,11 +174,14 @@ impl Adsr {
     pub fn new(sr: f32) -> Self {
         Self {
             sr: sr.max(1.0),
+            delay_samples: 0.0,
             a_samples: 0.0,
+            h_samples: 0.0,
             d_samples: 0.0,
             s_level: 1.0,
             r_samples: 0.0,
             level: 0.0,
+            counter: 0.0,
             state: AdsrState::Idle,
         }
     }
@@ -150,8 +197,19 @@ impl Adsr {
         self.r_samples = (r_ms.max(0.0) / 1000.0) * self.sr;
     }
 
+    /// Set the delay (before attack) and hold (after attack) stage times
+    pub fn set_delay_hold_ms(&mut self, delay_ms: f32, hold_ms: f32) {
+        self.delay_samples = (delay_ms.max(0.0) / 1000.0) * self.sr;
+        self.h_samples = (hold_ms.max(0.0) / 1000.0) * self.sr;
+    }
+
     pub fn note_on(&mut self) {
-        self.state = AdsrState::Attack;
+        self.counter = 0.0;
+        self.state = if self.delay_samples >= 1.0 {
+            AdsrState::Delay
+        } else {
+            AdsrState::Attack
+        };
         // Keep level for legato, but compute inc to take full attack time
     }
 
@@ -163,6 +221,7 @@ impl Adsr {
 
     pub fn reset(&mut self) {
         self.level = 0.0;
+        self.counter = 0.0;
         self.state = AdsrState::Idle;
     }
 
@@ -172,6 +231,12 @@ impl Adsr {
             AdsrState::Idle => {
                 self.level = 0.0;
             }
+            AdsrState::Delay => {
+                self.counter += 1.0;
+                if self.counter >= self.delay_samples {
+                    self.state = AdsrState::Attack;
+                }
+            }
             AdsrState::Attack => {
                 let inc = if self.a_samples <= 1.0 {
                     1.0
@@ -181,6 +246,17 @@ impl Adsr {
                 self.level += inc;
                 if self.level >= 1.0 {
                     self.level = 1.0;
+                    self.counter = 0.0;
+                    self.state = if self.h_samples >= 1.0 {
+                        AdsrState::Hold
+                    } else {
+                        AdsrState::Decay
+                    };
+                }
+            }
+            AdsrState::Hold => {
+                self.counter += 1.0;
+                if self.counter >= self.h_samples {
                     self.state = AdsrState::Decay;
                 }
             }
@@ -220,3 +296,216 @@ impl Adsr {
         self.state == AdsrState::Idle
     }
 }
+
+/// LFO waveforms, numbered as SFZ v2 `lfoN_wave`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LfoWave {
+    Triangle,
+    Sine,
+    Pulse75,
+    Square,
+    Pulse25,
+    Pulse12,
+    SawUp,
+    SawDown,
+}
+
+impl LfoWave {
+    pub fn from_sfz(wave: u32) -> Self {
+        match wave {
+            0 => LfoWave::Triangle,
+            2 => LfoWave::Pulse75,
+            3 => LfoWave::Square,
+            4 => LfoWave::Pulse25,
+            5 => LfoWave::Pulse12,
+            6 => LfoWave::SawUp,
+            7 => LfoWave::SawDown,
+            _ => LfoWave::Sine,
+        }
+    }
+
+    /// Value in -1..1 at a phase in 0..1
+    #[inline]
+    fn value(self, phase: f32) -> f32 {
+        let pulse = |width: f32| if phase < width { 1.0 } else { -1.0 };
+        match self {
+            LfoWave::Triangle => {
+                let p = (phase + 0.25).fract();
+                1.0 - 4.0 * (p - 0.5).abs()
+            }
+            LfoWave::Sine => (phase * 2.0 * PI).sin(),
+            LfoWave::Pulse75 => pulse(0.75),
+            LfoWave::Square => pulse(0.5),
+            LfoWave::Pulse25 => pulse(0.25),
+            LfoWave::Pulse12 => pulse(0.125),
+            LfoWave::SawUp => 2.0 * phase - 1.0,
+            LfoWave::SawDown => 1.0 - 2.0 * phase,
+        }
+    }
+}
+
+/// LFO with a delay and a linear fade-in, restarted per note
+pub struct Lfo {
+    sr: f32,
+    wave: LfoWave,
+    phase: f32,
+    inc: f32,
+    delay_samples: f32,
+    fade_samples: f32,
+    counter: f32,
+}
+
+impl Lfo {
+    pub fn new(sr: f32) -> Self {
+        Self {
+            sr: sr.max(1.0),
+            wave: LfoWave::Sine,
+            phase: 0.0,
+            inc: 0.0,
+            delay_samples: 0.0,
+            fade_samples: 0.0,
+            counter: 0.0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sr: f32) {
+        self.sr = sr.max(1.0);
+    }
+
+    /// Restart with delay and fade-in times in seconds
+    pub fn start(&mut self, delay: f32, fade: f32, freq: f32) {
+        self.phase = 0.0;
+        self.counter = 0.0;
+        self.delay_samples = delay.max(0.0) * self.sr;
+        self.fade_samples = fade.max(0.0) * self.sr;
+        self.set_freq(freq);
+    }
+
+    #[inline]
+    pub fn set_freq(&mut self, freq: f32) {
+        self.inc = freq.max(0.0) / self.sr;
+    }
+
+    /// Set the waveform and starting phase (0..1), after `start`
+    pub fn set_wave(&mut self, wave: LfoWave, phase: f32) {
+        self.wave = wave;
+        self.phase = phase.rem_euclid(1.0);
+    }
+
+    /// Next value in -1..1, scaled by the fade-in
+    #[inline]
+    pub fn next(&mut self) -> f32 {
+        if self.counter < self.delay_samples {
+            self.counter += 1.0;
+            return 0.0;
+        }
+        let elapsed = self.counter - self.delay_samples;
+        let fade = if elapsed < self.fade_samples {
+            self.counter += 1.0;
+            elapsed / self.fade_samples
+        } else {
+            1.0
+        };
+        let value = self.wave.value(self.phase) * fade;
+        self.phase += self.inc;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+        }
+        value
+    }
+}
+
+/// Multi-point envelope state (SFZ v2 `egN`). The (time, level) points live in the
+/// region definition and are passed to `next`.
+pub struct FlexEnv {
+    sr: f32,
+    stage: usize,
+    counter: f32,
+    level: f32,
+    /// Level at the start of the current stage
+    from: f32,
+    released: bool,
+}
+
+impl FlexEnv {
+    pub fn new(sr: f32) -> Self {
+        Self {
+            sr: sr.max(1.0),
+            stage: 0,
+            counter: 0.0,
+            level: 0.0,
+            from: 0.0,
+            released: false,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sr: f32) {
+        self.sr = sr.max(1.0);
+    }
+
+    pub fn start(&mut self) {
+        self.stage = 0;
+        self.counter = 0.0;
+        self.level = 0.0;
+        self.from = 0.0;
+        self.released = false;
+    }
+
+    /// Leave the sustain point
+    pub fn release(&mut self) {
+        self.released = true;
+    }
+
+    /// Next level, ramping linearly through `points` (time in seconds, level) and
+    /// holding at the `sustain` point until released
+    #[inline]
+    pub fn next(&mut self, points: &[(f32, f32)], sustain: Option<usize>) -> f32 {
+        let Some(&(time, target)) = points.get(self.stage) else {
+            return self.level;
+        };
+        let samples = time.max(0.0) * self.sr;
+        if self.counter < samples {
+            self.counter += 1.0;
+            self.level = lerp(self.from, target, self.counter / samples);
+        } else {
+            self.level = target;
+            if sustain != Some(self.stage) || self.released {
+                self.stage += 1;
+                self.counter = 0.0;
+                self.from = target;
+            }
+        }
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adsr_delay_and_hold_stages() {
+        // 1 sample per ms
+        let mut env = Adsr::new(1000.0);
+        env.set_ms(0.0, 0.0, 0.5, 0.0);
+        env.set_delay_hold_ms(10.0, 5.0);
+        env.note_on();
+        let out: Vec<f32> = (0..18).map(|_| env.next()).collect();
+        assert!(out[..10].iter().all(|&v| v == 0.0));
+        // Instant attack, then the hold keeps full level
+        assert!(out[10..16].iter().all(|&v| v == 1.0));
+        assert_eq!(out[16..], [0.5, 0.5]);
+    }
+
+    #[test]
+    fn adsr_note_off_during_delay_stays_silent() {
+        let mut env = Adsr::new(1000.0);
+        env.set_ms(0.0, 0.0, 1.0, 0.0);
+        env.set_delay_hold_ms(10.0, 0.0);
+        env.note_on();
+        env.next();
+        env.note_off();
+        assert_eq!(env.next(), 0.0);
+        assert!(env.is_idle());
+    }
+}
@@ -1,20 +1,29 @@
 mod dsp;
 mod loader;
+mod midi;
 mod params;
 mod sample;
 mod sfz;
 mod voice;
 
+use dsp::{Lfo, Rng};
+use midi::{ChannelState, KeyState, MOD_WHEEL_CC, MPE_MASTER_CHANNEL, MPE_TIMBRE_CC, SOSTENUTO_CC};
 use nih_plug::prelude::*;
-use params::SamploParams;
-use sample::{Instrument, RoundRobinState};
+use params::{SamploParams, VelCurveParam};
+use sample::{Instrument, LoopMode, OffMode, RoundRobinState, Trigger};
+use smallvec::SmallVec;
 use std::num::NonZeroU32;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, OnceLock};
-use voice::Voice;
+use voice::{Expression, Voice};
 
 pub(crate) const MAX_VOICES: usize = 64;
 pub(crate) const MAX_INSTRUMENTS: i32 = 1024;
+pub(crate) const MIDI_CHANNELS: usize = 16;
+/// Fade time for `off_mode=fast` chokes
+pub(crate) const FAST_OFF_MS: f32 = 6.0;
+/// Seed for `lorand`/`hirand` selection, restored on reset
+const RNG_SEED: u32 = 0x5a4d_504c;
 
 pub struct Samplo {
     params: Arc<SamploParams>,
@@ -22,9 +31,14 @@ pub struct Samplo {
     voices: Vec<Voice>,
     instrument: Instrument,
     rr_state: RoundRobinState,
+    rng: Rng,
+    /// Global LFO, shared by all voices so chords move together
+    lfo: Lfo,
+    channels: Vec<ChannelState>,
     frame_counter: u64,
 
     current_instrument_idx: usize,
+    current_keyswitch: i32,
     pending_instrument: Arc<Mutex<Option<Instrument>>>,
 }
 
@@ -37,8 +51,12 @@ impl Default for Samplo {
             voices: (0..MAX_VOICES).map(|_| Voice::new(sr)).collect(),
             instrument: Instrument::empty(),
             rr_state: RoundRobinState::new(),
+            rng: Rng::new(RNG_SEED),
+            lfo: Lfo::new(sr),
+            channels: (0..MIDI_CHANNELS).map(|_| ChannelState::new()).collect(),
             frame_counter: 0,
             current_instrument_idx: 0,
+            current_keyswitch: -1,
             pending_instrument: Arc::new(Mutex::new(None)),
         }
     }
@@ -59,7 +77,7 @@ impl Plugin for Samplo {
         names: PortNames::const_default(),
     }];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::Basic;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
@@ -77,6 +95,7 @@ impl Plugin for Samplo {
         _ctx: &mut impl InitContext<Self>,
     ) -> bool {
         self.sample_rate = buffer_config.sample_rate;
+        self.lfo.set_sample_rate(self.sample_rate);
 
         for voice in &mut self.voices {
             voice.set_sample_rate(self.sample_rate);
@@ -85,7 +104,7 @@ impl Plugin for Samplo {
         ensure_instruments_scanned();
 
         if self.instrument.regions.is_empty() {
-            self.instrument = loader::create_test_instrument(self.sample_rate);
+            self.instrument = loader::create_test_instrument();
             nih_log!("Loaded test sine instrument");
         }
 
@@ -95,6 +114,13 @@ impl Plugin for Samplo {
     fn reset(&mut self) {
         self.frame_counter = 0;
         self.rr_state.reset();
+        self.rng.reseed(RNG_SEED);
+        self.lfo.start(0.0, 0.0, 0.0);
+        self.instrument.reset_keyswitches();
+        self.current_keyswitch = -1;
+        for channel in &mut self.channels {
+            channel.reset();
+        }
         for voice in &mut self.voices {
             *voice = Voice::new(self.sample_rate);
         }
@@ -106,17 +132,24 @@ impl Plugin for Samplo {
         _aux: &mut AuxiliaryBuffers<'_>,
         ctx: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        self.frame_counter = self.frame_counter.wrapping_add(1);
-
         // Pick up instrument loaded in background
         if let Some(new_inst) = self.pending_instrument.lock().unwrap().take() {
             self.instrument = new_inst;
             self.rr_state.reset();
+            self.current_keyswitch = -1;
             nih_log!("Samplo: loaded instrument");
         }
 
         let params = self.params.clone();
 
+        let keyswitch = params.keyswitch.value();
+        if keyswitch != self.current_keyswitch {
+            self.current_keyswitch = keyswitch;
+            if keyswitch >= 0 {
+                self.instrument.select_keyswitch(keyswitch as u8);
+            }
+        }
+
         let inst_idx = params.instrument_index.value().max(0) as usize;
         if inst_idx != self.current_instrument_idx {
             self.current_instrument_idx = inst_idx;
@@ -132,21 +165,34 @@ impl Plugin for Samplo {
         let desired_voices = params.max_voices.value() as usize;
         self.resize_voice_pool(desired_voices);
 
+        // Follow bend range changes on held notes
+        self.update_pitch_bend();
+
         let attack = params.attack_ms.value();
         let decay = params.decay_ms.value();
         let sustain = params.sustain.value();
         let release = params.release_ms.value();
         let cutoff = params.cutoff_hz.value();
         let res = params.resonance.value();
+        let expr_cutoff = params.expr_cutoff_hz.value();
         let filter_mode = params.filter_mode.value().to_dsp();
+        let fil_attack = params.fil_attack_ms.value();
+        let fil_decay = params.fil_decay_ms.value();
+        let fil_sustain = params.fil_sustain.value();
+        let fil_release = params.fil_release_ms.value();
+        let fil_depth = params.fil_env_depth.value();
+        let lfo_rate = params.lfo_rate.value();
+        let lfo_depth = params.lfo_depth.value();
+        let lfo_target = params.lfo_target.value().to_target();
+        let lfo_mod_wheel = params.lfo_mod_wheel.value();
         let gain = params.gain.value();
         let pan = params.pan.value();
-        let tune = params.tune_cents.value();
-        let vel_sens = params.velocity_sens.value();
 
         let mut next_event = ctx.next_event();
 
         for (sample_idx, mut frame) in buffer.iter_samples().enumerate() {
+            self.frame_counter = self.frame_counter.wrapping_add(1);
+
             while let Some(ev) = next_event {
                 if ev.timing() != sample_idx as u32 {
                     break;
@@ -160,7 +206,7 @@ impl Plugin for Samplo {
                         voice_id,
                         ..
                     } => {
-                        self.note_on(channel, note, velocity, voice_id, tune, vel_sens);
+                        self.note_on(channel, note, velocity, voice_id);
                     }
                     NoteEvent::NoteOff {
                         channel,
@@ -170,6 +216,69 @@ impl Plugin for Samplo {
                     } => {
                         self.note_off(channel, note, voice_id);
                     }
+                    NoteEvent::MidiCC {
+                        channel, cc, value, ..
+                    } => {
+                        self.control_change(channel, cc, value);
+                    }
+                    NoteEvent::PolyTuning {
+                        voice_id,
+                        channel,
+                        note,
+                        tuning,
+                        ..
+                    } => {
+                        self.note_expression(voice_id, channel, note, Expression::Tuning(tuning));
+                    }
+                    NoteEvent::PolyVolume {
+                        voice_id,
+                        channel,
+                        note,
+                        gain,
+                        ..
+                    } => {
+                        self.note_expression(voice_id, channel, note, Expression::Volume(gain));
+                    }
+                    NoteEvent::PolyPan {
+                        voice_id,
+                        channel,
+                        note,
+                        pan,
+                        ..
+                    } => {
+                        self.note_expression(voice_id, channel, note, Expression::Pan(pan));
+                    }
+                    NoteEvent::PolyBrightness {
+                        voice_id,
+                        channel,
+                        note,
+                        brightness,
+                        ..
+                    } => {
+                        let expr = Expression::Brightness(brightness);
+                        self.note_expression(voice_id, channel, note, expr);
+                    }
+                    NoteEvent::PolyPressure {
+                        voice_id,
+                        channel,
+                        note,
+                        pressure,
+                        ..
+                    } => {
+                        let expr = Expression::Pressure(pressure);
+                        self.note_expression(voice_id, channel, note, expr);
+                    }
+                    NoteEvent::MidiPitchBend { channel, value, .. } => {
+                        self.channels[channel as usize % MIDI_CHANNELS].pitch_bend =
+                            (value * 2.0 - 1.0).clamp(-1.0, 1.0);
+                        self.update_pitch_bend();
+                    }
+                    NoteEvent::MidiChannelPressure {
+                        channel, pressure, ..
+                    } if self.is_mpe_member(channel) => {
+                        self.channels[channel as usize % MIDI_CHANNELS].pressure = pressure;
+                        self.channel_expression(channel, Expression::Pressure(pressure));
+                    }
                     _ => {}
                 }
 
@@ -179,25 +288,45 @@ impl Plugin for Samplo {
             let mut out_l = 0.0f32;
             let mut out_r = 0.0f32;
 
-            for voice in &mut self.voices {
+            self.lfo.set_freq(lfo_rate);
+            let lfo_value = self.lfo.next();
+
+            for i in 0..self.voices.len() {
+                let voice = &mut self.voices[i];
                 if !voice.active {
                     continue;
                 }
 
                 voice.set_env_ms(attack, decay, sustain, release);
+                voice.set_filter_env_ms(fil_attack, fil_decay, fil_sustain, fil_release, fil_depth);
+
+                let depth = if lfo_mod_wheel {
+                    let ch = voice.channel as usize % MIDI_CHANNELS;
+                    lfo_depth * self.channels[ch].cc[MOD_WHEEL_CC as usize]
+                } else {
+                    lfo_depth
+                };
+                voice.set_global_lfo(lfo_value * depth, lfo_target);
 
-                let (l, r) = voice.render(&self.instrument, cutoff, res, filter_mode);
+                let (l, r) = voice.render(&self.instrument, cutoff, res, filter_mode, expr_cutoff);
 
                 out_l += l;
                 out_r += r;
 
                 if !voice.active {
-                    ctx.send_event(NoteEvent::VoiceTerminated {
-                        timing: sample_idx as u32,
-                        voice_id: voice.note_id,
-                        channel: voice.channel,
-                        note: voice.note,
+                    // Layered voices of a note end together as far as the host is concerned
+                    let (voice_id, channel, note) = (voice.note_id, voice.channel, voice.note);
+                    let note_sounding = self.voices.iter().any(|v| {
+                        v.active && v.note_id == voice_id && v.channel == channel && v.note == note
                     });
+                    if !note_sounding {
+                        ctx.send_event(NoteEvent::VoiceTerminated {
+                            timing: sample_idx as u32,
+                            voice_id,
+                            channel,
+                            note,
+                        });
+                    }
                 }
             }
 
@@ -222,6 +351,7 @@ impl Plugin for Samplo {
 
     fn task_executor(&mut self) -> TaskExecutor<Self> {
         let pending = self.pending_instrument.clone();
+        let keyswitch_labels = self.params.keyswitch_labels.clone();
         Box::new(move |task| match task {
             BackgroundTask::LoadInstrument(path) => {
                 nih_log!("Loading instrument: {:?}", path);
@@ -236,6 +366,7 @@ impl Plugin for Samplo {
                 };
                 match result {
                     Ok(inst) => {
+                        *keyswitch_labels.lock().unwrap() = inst.sw_labels.clone();
                         *pending.lock().unwrap() = Some(inst);
                         nih_log!("Loaded instrument: {}", path.display());
                     }
@@ -279,34 +410,374 @@ impl Samplo {
         oldest_idx
     }
 
-    fn note_on(
+    fn note_on(&mut self, channel: u8, note: u8, velocity: f32, voice_id: Option<i32>) {
+        let midi_vel = (velocity.clamp(0.0, 1.0) * 127.0) as u8;
+
+        // Keyswitch notes only select articulations
+        if self.instrument.keyswitch_down(note) {
+            return;
+        }
+
+        let ch = channel as usize % MIDI_CHANNELS;
+        let legato = self.channels.iter().any(|c| c.any_key_down());
+        self.channels[ch].key_down(note, velocity, self.frame_counter);
+
+        // Layer every matching region, with round robin
+        let regions = self.instrument.find_regions(
+            note,
+            midi_vel,
+            legato,
+            &self.channels[ch].cc,
+            self.rng.next_f32(),
+            &mut self.rr_state,
+        );
+
+        self.choke_groups(&regions);
+        for region_idx in regions {
+            self.start_voice(channel, note, region_idx, 1.0, voice_id);
+        }
+    }
+
+    fn note_off(&mut self, channel: u8, note: u8, voice_id: Option<i32>) {
+        self.instrument.keyswitch_up(note);
+
+        let ch = channel as usize % MIDI_CHANNELS;
+        let key = self.channels[ch].key_up(note);
+
+        // A held pedal keeps the note sounding until it lifts; key-up triggers fire now
+        if key.down && (self.sustain_held(channel) || key.latched) {
+            self.channels[ch].keys[note as usize & 0x7f].pending_off = true;
+            self.trigger_release(channel, note, key, Trigger::ReleaseKey);
+            return;
+        }
+
+        self.release_voices(channel, note, voice_id);
+
+        if key.down {
+            self.trigger_release(channel, note, key, Trigger::ReleaseKey);
+            self.trigger_release(channel, note, key, Trigger::Release);
+        }
+    }
+
+    fn control_change(&mut self, channel: u8, cc: u8, value: f32) {
+        let ch = channel as usize % MIDI_CHANNELS;
+        self.channels[ch].set_cc(cc, value);
+        self.update_cc_modulation(channel);
+
+        if cc == MPE_TIMBRE_CC && self.is_mpe_member(channel) {
+            self.channels[ch].timbre = value;
+            self.channel_expression(channel, Expression::Brightness(value));
+        }
+
+        let level = value * 127.0;
+        if cc == self.instrument.sustain_cc {
+            let down = level >= self.instrument.sustain_lo;
+            if self.channels[ch].sustain != down {
+                self.channels[ch].sustain = down;
+                if !down {
+                    self.release_pedaled_notes(channel);
+                }
+            }
+        }
+
+        if cc == SOSTENUTO_CC {
+            let down = level >= 64.0;
+            if self.channels[ch].sostenuto != down {
+                self.channels[ch].sostenuto = down;
+                // Sostenuto only latches the keys held at the moment it goes down. The MPE
+                // master's pedal covers the zone; a member's own pedal keeps its latches.
+                for zone_ch in self.zone_channels(channel) {
+                    let state = &mut self.channels[zone_ch as usize % MIDI_CHANNELS];
+                    let own_pedal = zone_ch != channel && state.sostenuto;
+                    for key in state.keys.iter_mut() {
+                        if down {
+                            key.latched |= key.down;
+                        } else if !own_pedal {
+                            key.latched = false;
+                        }
+                    }
+                }
+                if !down {
+                    self.release_pedaled_notes(channel);
+                }
+            }
+        }
+    }
+
+    /// Follow CC changes in the region LFO depths (`*lfo_depthccN`) and CC crossfades
+    /// of a channel's voices
+    fn update_cc_modulation(&mut self, channel: u8) {
+        let cc = &self.channels[channel as usize % MIDI_CHANNELS].cc;
+        for voice in &mut self.voices {
+            if !voice.active || voice.channel != channel {
+                continue;
+            }
+            if let Some(region) = self.instrument.regions.get(voice.region_idx) {
+                voice.set_lfo_depths(
+                    region.amp_lfo.depth_at(cc),
+                    region.pitch_lfo.depth_at(cc),
+                    region.fil_lfo.depth_at(cc),
+                );
+                voice.set_cc_crossfade(region.crossfade.cc_gain(cc));
+            }
+        }
+    }
+
+    /// Release notes whose key is up and that are no longer held by a pedal, on the
+    /// channel or, for the MPE master, on its whole zone
+    fn release_pedaled_notes(&mut self, channel: u8) {
+        for zone_ch in self.zone_channels(channel) {
+            if self.sustain_held(zone_ch) {
+                continue;
+            }
+            let ch = zone_ch as usize % MIDI_CHANNELS;
+            for note in 0..128u8 {
+                let key = &mut self.channels[ch].keys[note as usize];
+                if !key.pending_off || key.latched {
+                    continue;
+                }
+                key.pending_off = false;
+                let key = *key;
+
+                self.release_voices(zone_ch, note, None);
+                self.trigger_release(zone_ch, note, key, Trigger::Release);
+            }
+        }
+    }
+
+    /// Sustain pedal state for notes on a channel; MPE members also follow the master
+    fn sustain_held(&self, channel: u8) -> bool {
+        self.channels[channel as usize % MIDI_CHANNELS].sustain
+            || (self.is_mpe_member(channel) && self.channels[MPE_MASTER_CHANNEL].sustain)
+    }
+
+    /// Channels a pedal on `channel` applies to: the whole zone for the MPE master
+    fn zone_channels(&self, channel: u8) -> std::ops::RangeInclusive<u8> {
+        if channel as usize == MPE_MASTER_CHANNEL && self.mpe_members() > 0 {
+            channel..=self.mpe_members()
+        } else {
+            channel..=channel
+        }
+    }
+
+    fn release_voices(&mut self, channel: u8, note: u8, voice_id: Option<i32>) {
+        for voice in &mut self.voices {
+            let one_shot = self
+                .instrument
+                .regions
+                .get(voice.region_idx)
+                .is_some_and(|r| r.loop_mode == LoopMode::OneShot);
+            if voice.active
+                && !one_shot
+                && !voice.release_triggered
+                && voice.channel == channel
+                && voice.note == note
+                && (voice_id.is_none() || voice.note_id == voice_id)
+            {
+                voice.release();
+            }
+        }
+    }
+
+    /// Start the release-triggered regions of a note that just ended
+    fn trigger_release(&mut self, channel: u8, note: u8, key: KeyState, trigger: Trigger) {
+        // Release triggers play with the original note-on velocity, attenuated
+        // by `rt_decay` dB for every second the key was held
+        let midi_vel = (key.velocity.clamp(0.0, 1.0) * 127.0) as u8;
+        let held_secs = self.frame_counter.saturating_sub(key.on_frame) as f32 / self.sample_rate;
+
+        let ch = channel as usize % MIDI_CHANNELS;
+        let release_regions =
+            self.instrument
+                .find_release_regions(note, midi_vel, trigger, &self.channels[ch].cc);
+        self.choke_groups(&release_regions);
+        for region_idx in release_regions {
+            let rt_decay = self.instrument.regions[region_idx].rt_decay;
+            let rt_gain = dsp::db_to_linear(-rt_decay * held_secs);
+            let slot = self.start_voice(channel, note, region_idx, rt_gain, None);
+            self.voices[slot].release_triggered = true;
+
+            // The key is already up, so looped release samples must not hang
+            if matches!(
+                self.instrument.regions[region_idx].loop_mode,
+                LoopMode::Continuous | LoopMode::Sustain
+            ) {
+                self.voices[slot].release();
+            }
+        }
+    }
+
+    /// Apply a note expression to the voices of a note, by voice ID when the host sends one
+    fn note_expression(&mut self, voice_id: Option<i32>, channel: u8, note: u8, expr: Expression) {
+        for voice in &mut self.voices {
+            let matches = match voice_id {
+                Some(id) => voice.note_id == Some(id),
+                None => voice.channel == channel && voice.note == note,
+            };
+            if voice.active && matches {
+                voice.set_expression(expr);
+            }
+        }
+    }
+
+    /// Retarget the pitch bend of all active voices
+    fn update_pitch_bend(&mut self) {
+        let range_cents = self.params.bend_range.value() as f32 * 100.0;
+        let bends: [(f32, f32); MIDI_CHANNELS] =
+            std::array::from_fn(|c| self.channel_bend(c as u8));
+        for voice in &mut self.voices {
+            if !voice.active {
+                continue;
+            }
+            let Some(region) = self.instrument.regions.get(voice.region_idx) else {
+                continue;
+            };
+            let (bend, note_cents) = bends[voice.channel as usize % MIDI_CHANNELS];
+            voice.set_bend(region.bend_cents(bend, range_cents) + note_cents, true);
+        }
+    }
+
+    /// Number of MPE member channels in the lower zone, 0 when MPE is off
+    fn mpe_members(&self) -> u8 {
+        if self.params.mpe_enabled.value() {
+            self.params.mpe_members.value() as u8
+        } else {
+            0
+        }
+    }
+
+    #[inline]
+    fn is_mpe_member(&self, channel: u8) -> bool {
+        channel >= 1 && channel <= self.mpe_members()
+    }
+
+    /// Bend for voices on a channel: (zone bend -1..1, per-note bend in cents).
+    /// MPE member channels follow the master channel's bend plus their own per-note bend.
+    fn channel_bend(&self, channel: u8) -> (f32, f32) {
+        let own = self.channels[channel as usize % MIDI_CHANNELS].pitch_bend;
+        if self.is_mpe_member(channel) {
+            let note_range = self.params.mpe_bend_range.value() as f32 * 100.0;
+            (
+                self.channels[MPE_MASTER_CHANNEL].pitch_bend,
+                own * note_range,
+            )
+        } else {
+            (own, 0.0)
+        }
+    }
+
+    /// Apply an expression to every voice started on a channel (MPE member channels)
+    fn channel_expression(&mut self, channel: u8, expr: Expression) {
+        for voice in &mut self.voices {
+            if voice.active && voice.channel == channel {
+                voice.set_expression(expr);
+            }
+        }
+    }
+
+    /// Choke the groups of the regions about to start, once per group, so the layers
+    /// of a self-choking region (`group=N off_by=N`) don't cut each other off
+    fn choke_groups(&mut self, regions: &[usize]) {
+        let mut choked: SmallVec<[u32; 4]> = SmallVec::new();
+        for &idx in regions {
+            let group = self.instrument.regions[idx].group;
+            if !choked.contains(&group) {
+                choked.push(group);
+                self.choke_group(group);
+            }
+        }
+    }
+
+    /// Turn off voices whose region has `off_by` set to the given group
+    fn choke_group(&mut self, group: u32) {
+        for voice in &mut self.voices {
+            if !voice.active {
+                continue;
+            }
+            let Some(region) = self.instrument.regions.get(voice.region_idx) else {
+                continue;
+            };
+            if region.off_by != Some(group) {
+                continue;
+            }
+            voice.choke(match region.off_mode {
+                OffMode::Fast => Some(FAST_OFF_MS),
+                OffMode::Normal => None,
+                OffMode::Time => Some(region.off_time * 1000.0),
+            });
+        }
+    }
+
+    /// Shape a note velocity (0..1) with the velocity curve param
+    fn curve_velocity(&self, velocity: f32) -> f32 {
+        let v = velocity.clamp(0.0, 1.0);
+        match self.params.velocity_curve.value() {
+            VelCurveParam::Linear => v,
+            VelCurveParam::Exponential => v * v,
+            VelCurveParam::Logarithmic => (1.0 + 9.0 * v).log10(),
+            VelCurveParam::SCurve => v * v * (3.0 - 2.0 * v),
+            VelCurveParam::Custom => {
+                let points = [
+                    0.0,
+                    self.params.velocity_point1.value(),
+                    self.params.velocity_point2.value(),
+                    self.params.velocity_point3.value(),
+                    1.0,
+                ];
+                let pos = v * 4.0;
+                let i = (pos as usize).min(3);
+                dsp::lerp(points[i], points[i + 1], pos - i as f32)
+            }
+        }
+    }
+
+    /// Start a voice for a region, with its velocity response scaled by `gain`
+    fn start_voice(
         &mut self,
         channel: u8,
         note: u8,
-        velocity: f32,
+        region_idx: usize,
+        gain: f32,
         voice_id: Option<i32>,
-        tune_cents: f32,
-        vel_sens: f32,
-    ) {
-        let midi_vel = (velocity.clamp(0.0, 1.0) * 127.0) as u8;
-
-        // Find matching region with round robin
-        let region_idx = match self
-            .instrument
-            .find_region(note, midi_vel, &mut self.rr_state)
-        {
-            Some(idx) => idx,
-            None => return,
-        };
-
+    ) -> usize {
         let region = &self.instrument.regions[region_idx];
+        let ch = &self.channels[channel as usize % MIDI_CHANNELS];
 
+        let tune_cents = self.params.tune_cents.value();
         let tune_ratio = 2.0f64.powf(tune_cents as f64 / 1200.0);
-        let playback_rate = region.playback_rate(note, self.sample_rate) * tune_ratio;
-
-        let vel_amount = 1.0 - vel_sens + vel_sens * velocity;
+        let velocity = ch.keys[note as usize & 0x7f].velocity;
+        let playback_rate = region.playback_rate(note, velocity, self.sample_rate) * tune_ratio;
+        let vel_sens = self.params.velocity_sens.value();
+        let vel_amount = region.amp_velocity(self.curve_velocity(velocity), vel_sens) * gain;
+        let amp_env = region.amp_env.resolve(velocity, &ch.cc);
+        let fil_eg = region.fil_env.resolve(velocity, &ch.cc);
+        let fil_depth = region.fil_env.depth_at(velocity);
+        let pitch_eg = region.pitch_env.resolve(velocity, &ch.cc);
+        let pitch_depth = region.pitch_env.depth_at(velocity);
+        let start_frame = region.start_frame(&ch.cc, self.rng.next_f32());
+        let delay = region.delay + region.delay_random * self.rng.next_f32();
+        let delay_samples = (delay * self.sample_rate) as u32;
+        let noise_seed = self.rng.next_u32();
+        let xf_note_gain = region
+            .crossfade
+            .note_gain(note, (velocity.clamp(0.0, 1.0) * 127.0) as u8);
+        let xf_cc_gain = region.crossfade.cc_gain(&ch.cc);
+        let lfo_depths = (
+            region.amp_lfo.depth_at(&ch.cc),
+            region.pitch_lfo.depth_at(&ch.cc),
+            region.fil_lfo.depth_at(&ch.cc),
+        );
+        let filter = region
+            .filter
+            .map(|f| (f.mode, f.cutoff_hz(note, velocity), f.q()));
+        let bend_range = self.params.bend_range.value() as f32 * 100.0;
+        let (bend, note_cents) = self.channel_bend(channel);
+        let bend_cents = region.bend_cents(bend, bend_range) + note_cents;
+        let mpe_member = self.is_mpe_member(channel);
+        let (pressure, timbre) = (ch.pressure, ch.timbre);
 
         let slot = self.alloc_voice();
+        let region = &self.instrument.regions[region_idx];
         let voice = &mut self.voices[slot];
         voice.start(
             channel,
@@ -314,21 +785,28 @@ impl Samplo {
             vel_amount,
             region_idx,
             playback_rate,
+            amp_env,
             self.frame_counter,
         );
         voice.note_id = voice_id;
-    }
-
-    fn note_off(&mut self, channel: u8, note: u8, voice_id: Option<i32>) {
-        for voice in &mut self.voices {
-            if voice.active
-                && voice.channel == channel
-                && voice.note == note
-                && (voice_id.is_none() || voice.note_id == voice_id)
-            {
-                voice.release();
-            }
+        voice.set_start(start_frame, region.reverse, delay_samples);
+        voice.set_noise_seed(noise_seed);
+        voice.set_bend(bend_cents, false);
+        voice.set_filter_env(fil_eg, fil_depth);
+        voice.set_pitch_env(pitch_eg, pitch_depth);
+        voice.start_lfos(&region.amp_lfo, &region.pitch_lfo, &region.fil_lfo);
+        voice.start_flex(&region.flex_lfos);
+        voice.set_crossfade(xf_note_gain, xf_cc_gain);
+        voice.set_lfo_depths(lfo_depths.0, lfo_depths.1, lfo_depths.2);
+        if let Some((mode, cutoff, q)) = filter {
+            voice.set_region_filter(mode, cutoff, q);
+        }
+        // MPE controllers send the initial pressure and timbre before the note-on
+        if mpe_member {
+            voice.set_expression(Expression::Pressure(pressure));
+            voice.set_expression(Expression::Brightness(timbre));
         }
+        slot
     }
 }
 
@@ -417,3 +895,129 @@ pub fn instrument_name_for_index(idx: i32) -> String {
         .map(|s| s.name.clone())
         .unwrap_or_else(|| "None".to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midi::SUSTAIN_CC;
+
+    fn sounding(plugin: &Samplo) -> usize {
+        plugin
+            .voices
+            .iter()
+            .filter(|v| v.active && !v.releasing)
+            .count()
+    }
+
+    #[test]
+    fn layered_self_choking_regions_all_play() {
+        let regions = (0..2)
+            .map(|_| {
+                let mut r = loader::create_test_instrument().regions.remove(0);
+                r.group = 1;
+                r.off_by = Some(1);
+                r
+            })
+            .collect();
+        let mut plugin = Samplo {
+            instrument: Instrument::new(String::from("hats"), regions),
+            ..Samplo::default()
+        };
+
+        plugin.note_on(0, 60, 1.0, None);
+        assert_eq!(sounding(&plugin), 2);
+
+        // The next hit chokes both earlier layers and keeps both of its own
+        plugin.note_on(0, 60, 1.0, None);
+        assert_eq!(sounding(&plugin), 2);
+        assert_eq!(plugin.voices.iter().filter(|v| v.releasing).count(), 2);
+    }
+
+    #[test]
+    fn layered_noise_regions_differ() {
+        let regions = (0..2)
+            .map(|_| {
+                let mut r = loader::create_test_instrument().regions.remove(0);
+                r.generator = loader::generate_audio("noise").unwrap().generator;
+                r
+            })
+            .collect();
+        let mut plugin = Samplo {
+            instrument: Instrument::new(String::from("noise"), regions),
+            ..Samplo::default()
+        };
+
+        plugin.note_on(0, 60, 1.0, None);
+        let Samplo {
+            voices, instrument, ..
+        } = &mut plugin;
+        let mut layers: Vec<_> = voices.iter_mut().filter(|v| v.active).collect();
+        assert_eq!(layers.len(), 2);
+        let outputs: Vec<Vec<(f32, f32)>> = layers
+            .iter_mut()
+            .map(|v| {
+                (0..64)
+                    .map(|_| v.render(instrument, 20000.0, 0.7, dsp::FilterMode::Off, 20000.0))
+                    .collect()
+            })
+            .collect();
+        assert!(outputs[0].iter().any(|&(l, _)| l != 0.0));
+        assert_ne!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn restruck_key_stays_latched_by_sostenuto() {
+        let mut plugin = Samplo {
+            instrument: loader::create_test_instrument(),
+            ..Samplo::default()
+        };
+        plugin.note_on(0, 60, 1.0, None);
+        plugin.control_change(0, SOSTENUTO_CC, 1.0);
+        plugin.note_off(0, 60, None);
+        plugin.note_on(0, 60, 1.0, None);
+        plugin.note_off(0, 60, None);
+        assert!(sounding(&plugin) > 0);
+
+        plugin.control_change(0, SOSTENUTO_CC, 0.0);
+        assert_eq!(sounding(&plugin), 0);
+    }
+
+    fn mpe_plugin() -> Samplo {
+        let params = SamploParams {
+            mpe_enabled: BoolParam::new("MPE", true),
+            ..SamploParams::default()
+        };
+        Samplo {
+            params: Arc::new(params),
+            instrument: loader::create_test_instrument(),
+            ..Samplo::default()
+        }
+    }
+
+    #[test]
+    fn master_sustain_holds_member_notes() {
+        let mut plugin = mpe_plugin();
+        plugin.note_on(1, 60, 1.0, None);
+        plugin.control_change(MPE_MASTER_CHANNEL as u8, SUSTAIN_CC, 1.0);
+        plugin.note_off(1, 60, None);
+        assert_eq!(sounding(&plugin), 1);
+
+        plugin.control_change(MPE_MASTER_CHANNEL as u8, SUSTAIN_CC, 0.0);
+        assert_eq!(sounding(&plugin), 0);
+    }
+
+    #[test]
+    fn master_sostenuto_latches_member_notes() {
+        let mut plugin = mpe_plugin();
+        plugin.note_on(2, 60, 1.0, None);
+        plugin.control_change(MPE_MASTER_CHANNEL as u8, SOSTENUTO_CC, 1.0);
+        // Keys pressed after the pedal went down aren't held
+        plugin.note_on(3, 64, 1.0, None);
+        plugin.note_off(3, 64, None);
+        plugin.note_off(2, 60, None);
+        assert_eq!(sounding(&plugin), 1);
+
+        plugin.control_change(MPE_MASTER_CHANNEL as u8, SOSTENUTO_CC, 0.0);
+        assert_eq!(sounding(&plugin), 0);
+    }
+}
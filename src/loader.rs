@@ -1,6 +1,10 @@
-use crate::sample::{Instrument, InstrumentDef, Region, RegionDef};
+use crate::sample::{
+    CrossfadeDef, EnvelopeDef, Generator, Instrument, InstrumentDef, KeyswitchDef, LfoDef,
+    LoopType, OffMode, Region, RegionDef, Trigger, Wavetable,
+};
+use smallvec::SmallVec;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -29,6 +33,21 @@ pub struct AudioData {
     pub channels: usize,
     pub sample_rate: u32,
     pub num_frames: usize,
+    pub meta: SampleMeta,
+    /// Built-in waveform for generated samples
+    pub generator: Option<Arc<Generator>>,
+}
+
+/// Sampler metadata stored in the file (WAV `smpl`, `inst` and `acid` chunks)
+#[derive(Clone, Copy, Default)]
+pub struct SampleMeta {
+    /// MIDI note the sample plays unshifted
+    pub root_note: Option<u8>,
+    /// Tuning in cents that brings the sample onto `root_note`
+    pub tune_cents: Option<f32>,
+    /// First loop as start and exclusive end frames
+    pub loop_range: Option<(usize, usize)>,
+    pub loop_type: LoopType,
 }
 
 /// Load an audio file using Symphonia
@@ -143,9 +162,96 @@ pub fn load_audio(path: &Path) -> Result<AudioData, String> {
         channels,
         sample_rate,
         num_frames,
+        meta: read_wav_meta(path).unwrap_or_default(),
+        generator: None,
+    })
+}
+
+/// Read sampler metadata from a WAV file's chunks; other formats have none
+fn read_wav_meta(path: &Path) -> std::io::Result<SampleMeta> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Ok(SampleMeta::default());
+    }
+
+    let mut smpl = SampleMeta::default();
+    let mut inst = SampleMeta::default();
+    let mut acid_root = None;
+    let mut chunk = [0u8; 8];
+    while file.read_exact(&mut chunk).is_ok() {
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+        // Chunks are padded to an even length
+        let padded = size + (size & 1);
+        let id = &chunk[0..4];
+        if !matches!(id, b"smpl" | b"inst" | b"acid") || size > 0x10000 {
+            file.seek(SeekFrom::Current(padded as i64))?;
+            continue;
+        }
+        let mut body = vec![0u8; padded];
+        file.read_exact(&mut body)?;
+        match id {
+            b"smpl" => smpl = parse_smpl(&body),
+            b"inst" if body.len() >= 2 => {
+                inst.root_note = Some(body[0]).filter(|&n| n < 128);
+                inst.tune_cents = Some(body[1] as i8 as f32);
+            }
+            b"acid" => {
+                // Flag 0x02: the root note is set
+                if let (Some(flags), Some(root)) = (le_u32(&body, 0), body.get(4))
+                    && flags & 0x02 != 0
+                    && *root < 128
+                {
+                    acid_root = Some(*root);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // `smpl` is the most complete, then `inst`; `acid` only has a root note
+    let tuned = if smpl.root_note.is_some() { smpl } else { inst };
+    Ok(SampleMeta {
+        root_note: tuned.root_note.or(acid_root),
+        tune_cents: tuned.root_note.and(tuned.tune_cents),
+        loop_range: smpl.loop_range,
+        loop_type: smpl.loop_type,
     })
 }
 
+fn parse_smpl(body: &[u8]) -> SampleMeta {
+    let mut meta = SampleMeta::default();
+    if let Some(unity) = le_u32(body, 12)
+        && unity < 128
+    {
+        meta.root_note = Some(unity as u8);
+        // The pitch fraction says how far the sample is above the unity note
+        let fraction = le_u32(body, 16).unwrap_or(0);
+        meta.tune_cents = Some(-(fraction as f64 / 4294967296.0 * 100.0) as f32);
+    }
+
+    // First loop: cue id, type, start, inclusive end, fraction, play count
+    if le_u32(body, 28).unwrap_or(0) > 0
+        && let (Some(kind), Some(start), Some(end)) =
+            (le_u32(body, 40), le_u32(body, 44), le_u32(body, 48))
+        && end >= start
+    {
+        meta.loop_range = Some((start as usize, end as usize + 1));
+        meta.loop_type = match kind {
+            1 => LoopType::Alternate,
+            2 => LoopType::Backward,
+            _ => LoopType::Forward,
+        };
+    }
+    meta
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
 fn append_samples(buffer: &GenericAudioBufferRef, out: &mut Vec<f32>, channels: usize) {
     match *buffer {
         GenericAudioBufferRef::F32(ref buf) => {
@@ -235,41 +341,104 @@ pub fn load_instrument_json(def_path: &Path) -> Result<Instrument, String> {
 }
 
 fn load_region(sample_path: &Path, def: &RegionDef) -> Result<Region, String> {
-    let audio = load_audio(sample_path)?;
+    let audio = match def.sample.strip_prefix('*') {
+        Some(wave) => generate_audio(wave)
+            .ok_or_else(|| format!("Unknown built-in sample '{}'", def.sample))?,
+        None => load_audio(sample_path)?,
+    };
+    let meta = audio.meta;
 
     use crate::sample::LoopMode;
 
-    Ok(Region {
+    // Loop points and root note fall back to the sample's own metadata
+    let (loop_start, loop_end) = match (def.loop_start, def.loop_end) {
+        (None, None) => meta.loop_range.unzip(),
+        points => points,
+    };
+    let loop_enabled = def.loop_enabled.unwrap_or(meta.loop_range.is_some());
+    // The file's fine tuning belongs to its own root note
+    let file_tune = meta.tune_cents.filter(|_| def.root.is_none());
+
+    let mut region = Region {
         data: Arc::new(audio.samples),
         channels: audio.channels,
         sample_rate: audio.sample_rate as f32,
         num_frames: audio.num_frames,
+        generator: audio.generator,
 
-        root_note: def.root,
+        root_note: def.root.or(meta.root_note).unwrap_or(60),
         lo_note: def.lo_note.unwrap_or(0),
         hi_note: def.hi_note.unwrap_or(127),
         lo_vel: def.lo_vel.unwrap_or(0),
         hi_vel: def.hi_vel.unwrap_or(127),
 
-        loop_start: def.loop_start,
-        loop_end: def.loop_end,
-        loop_mode: if def.loop_enabled {
+        offset: 0,
+        offset_random: 0,
+        offset_cc: SmallVec::new(),
+        end: audio.num_frames,
+        reverse: false,
+        delay: 0.0,
+        delay_random: 0.0,
+
+        loop_start,
+        loop_end,
+        loop_mode: if loop_enabled {
             LoopMode::Continuous
         } else {
             LoopMode::NoLoop
         },
+        loop_type: def.loop_type.unwrap_or(meta.loop_type),
+        loop_count: None,
+        loop_crossfade: def.loop_crossfade,
+
+        group: 0,
+        off_by: None,
+        off_mode: OffMode::Fast,
+        off_time: 0.0,
 
         rr_group: def.rr_group,
         rr_seq: def.rr_seq,
-
-        tune_cents: def.tune_cents,
+        rr_length: None,
+        lo_rand: 0.0,
+        hi_rand: 1.0,
+
+        trigger: Trigger::Attack,
+        rt_decay: 0.0,
+
+        amp_env: EnvelopeDef::default(),
+        amp_veltrack: None,
+        amp_velcurve: SmallVec::new(),
+        filter: None,
+        fil_env: EnvelopeDef::default(),
+        pitch_env: EnvelopeDef::default(),
+        amp_lfo: LfoDef::default(),
+        pitch_lfo: LfoDef::default(),
+        fil_lfo: LfoDef::default(),
+        flex_egs: SmallVec::new(),
+        flex_lfos: SmallVec::new(),
+        crossfade: CrossfadeDef::default(),
+        cc_ranges: SmallVec::new(),
+        keyswitch: KeyswitchDef::default(),
+
+        bend_up: None,
+        bend_down: None,
+        bend_step: 0.0,
+
+        pitch_keytrack: 100.0,
+        transpose: 0,
+        pitch_veltrack: 0.0,
+        tune_cents: def.tune_cents.or(file_tune).unwrap_or(0.0),
         volume_db: def.volume_db,
         volume_lin: crate::dsp::db_to_linear(def.volume_db),
         pan: def.pan,
 
         #[cfg(debug_assertions)]
         sample_path: sample_path.to_string_lossy().to_string(),
-    })
+    };
+    if let Some(fix) = region.validate_loop() {
+        nih_plug::nih_log!("Loop points fixed ({}): {}", fix, sample_path.display());
+    }
+    Ok(region)
 }
 
 /// Scan a directory for instrument files (.json or .sfz)
@@ -326,42 +495,144 @@ fn is_instrument_file(path: &Path) -> bool {
     }
 }
 
-/// Create a test instrument with a sine wave
-pub fn create_test_instrument(sample_rate: f32) -> Instrument {
-    use core::f32::consts::PI;
+/// Generated waveforms are tuned to this note
+const GENERATOR_ROOT: u8 = 60;
+const SILENCE_RATE: u32 = 44100;
+
+/// Synthesize a built-in sample (SFZ `sample=*name`): a looped single cycle of `sine`,
+/// `saw`, `square` or `triangle` backed by per-octave band-limited tables, or a looped
+/// silent buffer for `silence` and for `noise`, which the voice generates live
+pub fn generate_audio(name: &str) -> Option<AudioData> {
+    // Amplitude of harmonic `h`
+    let harmonic: fn(usize) -> f32 = match name {
+        "sine" => |h| if h == 1 { 1.0 } else { 0.0 },
+        "saw" => |h| 1.0 / h as f32,
+        "square" => |h| if h % 2 == 1 { 1.0 / h as f32 } else { 0.0 },
+        "triangle" => |h| match h % 4 {
+            1 => 1.0 / (h * h) as f32,
+            3 => -1.0 / (h * h) as f32,
+            _ => 0.0,
+        },
+        "noise" => {
+            return Some(generated(
+                silence(),
+                SILENCE_RATE,
+                None,
+                Some(Generator::Noise),
+            ));
+        }
+        "silence" => return Some(generated(silence(), SILENCE_RATE, None, None)),
+        _ => return None,
+    };
 
-    let duration_secs = 1.0;
-    let num_frames = (sample_rate * duration_secs) as usize;
-    let freq = 440.0;
+    let wavetable = Wavetable::new(harmonic);
+    // One cycle per `Wavetable::CYCLE` frames plays the root note
+    let root_hz = 440.0 * 2.0f32.powf((GENERATOR_ROOT as f32 - 69.0) / 12.0);
+    let sample_rate = (Wavetable::CYCLE as f32 * root_hz).round() as u32;
+    Some(generated(
+        wavetable.table(1.0).to_vec(),
+        sample_rate,
+        Some(GENERATOR_ROOT),
+        Some(Generator::Wavetable(wavetable)),
+    ))
+}
+
+fn silence() -> Vec<f32> {
+    vec![0.0; Wavetable::CYCLE]
+}
 
-    let mut data = Vec::with_capacity(num_frames);
-    for i in 0..num_frames {
-        let t = i as f32 / sample_rate;
-        let sample = (2.0 * PI * freq * t).sin() * 0.8;
-        data.push(sample);
+fn generated(
+    samples: Vec<f32>,
+    sample_rate: u32,
+    root_note: Option<u8>,
+    generator: Option<Generator>,
+) -> AudioData {
+    let num_frames = samples.len();
+    AudioData {
+        samples,
+        channels: 1,
+        sample_rate,
+        num_frames,
+        meta: SampleMeta {
+            root_note,
+            loop_range: Some((0, num_frames)),
+            ..SampleMeta::default()
+        },
+        generator: generator.map(Arc::new),
     }
+}
 
-    use crate::sample::LoopMode;
+/// Create a test instrument with a generated sine wave
+pub fn create_test_instrument() -> Instrument {
+    let audio = generate_audio("sine").expect("built-in waveform");
+    let num_frames = audio.num_frames;
+
+    use crate::sample::{LoopMode, LoopType};
 
     let region = Region {
-        data: Arc::new(data),
+        data: Arc::new(audio.samples),
         channels: 1,
-        sample_rate,
+        sample_rate: audio.sample_rate as f32,
         num_frames,
+        generator: audio.generator,
 
-        root_note: 69,
+        root_note: GENERATOR_ROOT,
         lo_note: 0,
         hi_note: 127,
         lo_vel: 0,
         hi_vel: 127,
 
-        loop_start: Some((sample_rate * 0.1) as usize),
-        loop_end: Some((sample_rate * 0.9) as usize),
+        offset: 0,
+        offset_random: 0,
+        offset_cc: SmallVec::new(),
+        end: num_frames,
+        reverse: false,
+        delay: 0.0,
+        delay_random: 0.0,
+
+        loop_start: Some(0),
+        loop_end: Some(num_frames),
         loop_mode: LoopMode::Continuous,
+        loop_type: LoopType::Forward,
+        loop_count: None,
+        loop_crossfade: 0.0,
+
+        group: 0,
+        off_by: None,
+        off_mode: OffMode::Fast,
+        off_time: 0.0,
 
         rr_group: 0,
         rr_seq: 0,
-
+        rr_length: None,
+        lo_rand: 0.0,
+        hi_rand: 1.0,
+
+        trigger: Trigger::Attack,
+        rt_decay: 0.0,
+
+        amp_env: EnvelopeDef::default(),
+        amp_veltrack: None,
+        amp_velcurve: SmallVec::new(),
+        filter: None,
+        fil_env: EnvelopeDef::default(),
+        pitch_env: EnvelopeDef::default(),
+        amp_lfo: LfoDef::default(),
+        pitch_lfo: LfoDef::default(),
+        fil_lfo: LfoDef::default(),
+        flex_egs: SmallVec::new(),
+        flex_lfos: SmallVec::new(),
+        crossfade: CrossfadeDef::default(),
+        cc_ranges: SmallVec::new(),
+        keyswitch: KeyswitchDef::default(),
+
+        bend_up: None,
+        bend_down: None,
+        bend_step: 0.0,
+
+        pitch_keytrack: 100.0,
+        transpose: 0,
+        pitch_veltrack: 0.0,
         tune_cents: 0.0,
         volume_db: 0.0,
         volume_lin: crate::dsp::db_to_linear(0.0),
@@ -373,3 +644,94 @@ pub fn create_test_instrument(sample_rate: f32) -> Instrument {
 
     Instrument::new(String::from("Test Sine"), vec![region])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn smpl(unity: u32, fraction: u32, loop_kind: u32, start: u32, end: u32) -> Vec<u8> {
+        let mut words = vec![0u32; 9];
+        words[3] = unity;
+        words[4] = fraction;
+        words[7] = 1;
+        words.extend_from_slice(&[0, loop_kind, start, end, 0, 0]);
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    /// Write a WAV header with the given chunks and read its metadata back
+    fn wav_meta(name: &str, chunks: &[Vec<u8>]) -> SampleMeta {
+        let body: Vec<u8> = chunks.concat();
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(&body);
+        let path = std::env::temp_dir().join(format!("samplo-{}-{name}.wav", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let meta = read_wav_meta(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        meta
+    }
+
+    #[test]
+    fn smpl_gives_root_tune_and_inclusive_loop() {
+        let meta = parse_smpl(&smpl(62, 0x8000_0000, 1, 10, 20));
+        assert_eq!(meta.root_note, Some(62));
+        assert_eq!(meta.tune_cents, Some(-50.0));
+        assert_eq!(meta.loop_range, Some((10, 21)));
+        assert!(meta.loop_type == LoopType::Alternate);
+    }
+
+    #[test]
+    fn smpl_without_unity_note_or_loops() {
+        let mut body = smpl(200, 0, 0, 0, 0);
+        body[28..32].copy_from_slice(&0u32.to_le_bytes());
+        let meta = parse_smpl(&body);
+        assert_eq!(meta.root_note, None);
+        assert_eq!(meta.tune_cents, None);
+        assert_eq!(meta.loop_range, None);
+    }
+
+    #[test]
+    fn smpl_wins_over_inst_and_acid() {
+        let meta = wav_meta(
+            "smpl",
+            &[
+                chunk(b"odd ", &[1, 2, 3]),
+                chunk(b"inst", &[64, (-7i8) as u8, 0, 0, 127, 0, 127]),
+                chunk(b"acid", &[0x02, 0, 0, 0, 57, 0]),
+                chunk(b"smpl", &smpl(62, 0, 0, 0, 99)),
+            ],
+        );
+        assert_eq!(meta.root_note, Some(62));
+        assert_eq!(meta.tune_cents, Some(0.0));
+        assert_eq!(meta.loop_range, Some((0, 100)));
+    }
+
+    #[test]
+    fn inst_then_acid_root_note() {
+        let meta = wav_meta(
+            "inst",
+            &[chunk(b"inst", &[64, (-7i8) as u8, 0, 0, 127, 0, 127])],
+        );
+        assert_eq!(meta.root_note, Some(64));
+        assert_eq!(meta.tune_cents, Some(-7.0));
+
+        let meta = wav_meta("acid", &[chunk(b"acid", &[0x02, 0, 0, 0, 57, 0])]);
+        assert_eq!(meta.root_note, Some(57));
+        assert_eq!(meta.tune_cents, None);
+
+        // Without the root note flag the note is ignored
+        let meta = wav_meta("acid-unset", &[chunk(b"acid", &[0x01, 0, 0, 0, 57, 0])]);
+        assert_eq!(meta.root_note, None);
+    }
+}
@@ -0,0 +1,102 @@
+//! Per-channel MIDI state tracked by the voice engine
+
+pub const MOD_WHEEL_CC: u8 = 1;
+pub const SUSTAIN_CC: u8 = 64;
+pub const SOSTENUTO_CC: u8 = 66;
+
+/// MPE lower zone: channel 1 (index 0) is the master, member channels follow it
+pub const MPE_MASTER_CHANNEL: usize = 0;
+/// MPE third dimension ("timbre"), mapped to brightness
+pub const MPE_TIMBRE_CC: u8 = 74;
+
+/// State of a single key on a channel
+#[derive(Clone, Copy, Default)]
+pub struct KeyState {
+    pub down: bool,
+    /// Note-on velocity (0..1)
+    pub velocity: f32,
+    /// Frame counter value at note-on
+    pub on_frame: u64,
+    /// Held by the sostenuto pedal
+    pub latched: bool,
+    /// Key is up but the note is held by a pedal
+    pub pending_off: bool,
+}
+
+pub struct ChannelState {
+    pub keys: [KeyState; 128],
+    /// Current controller values (0..1)
+    pub cc: [f32; 128],
+    pub sustain: bool,
+    pub sostenuto: bool,
+    /// Pitch bend position (-1..1)
+    pub pitch_bend: f32,
+    /// Channel pressure (0..1), used in MPE mode
+    pub pressure: f32,
+    /// Last CC74 value (0..1), used in MPE mode
+    pub timbre: f32,
+}
+
+impl ChannelState {
+    pub fn new() -> Self {
+        Self {
+            keys: [KeyState::default(); 128],
+            cc: default_cc_values(),
+            sustain: false,
+            sostenuto: false,
+            pitch_bend: 0.0,
+            pressure: 0.0,
+            timbre: 0.5,
+        }
+    }
+
+    #[inline]
+    pub fn set_cc(&mut self, cc: u8, value: f32) {
+        self.cc[cc as usize & 0x7f] = value.clamp(0.0, 1.0);
+    }
+
+    /// Press a key. A key latched by a sostenuto pedal that is still down stays latched;
+    /// latches are cleared when the pedal lifts.
+    pub fn key_down(&mut self, note: u8, velocity: f32, frame: u64) {
+        let key = &mut self.keys[note as usize & 0x7f];
+        *key = KeyState {
+            down: true,
+            velocity,
+            on_frame: frame,
+            latched: key.latched,
+            pending_off: false,
+        };
+    }
+
+    /// Mark a key as released, returning its state before the release
+    pub fn key_up(&mut self, note: u8) -> KeyState {
+        let key = &mut self.keys[note as usize & 0x7f];
+        let prev = *key;
+        key.down = false;
+        prev
+    }
+
+    #[inline]
+    pub fn any_key_down(&self) -> bool {
+        self.keys.iter().any(|k| k.down)
+    }
+
+    pub fn reset(&mut self) {
+        self.keys = [KeyState::default(); 128];
+        self.cc = default_cc_values();
+        self.sustain = false;
+        self.sostenuto = false;
+        self.pitch_bend = 0.0;
+        self.pressure = 0.0;
+        self.timbre = 0.5;
+    }
+}
+
+/// Power-on controller values: volume 100, pan centered, expression full
+fn default_cc_values() -> [f32; 128] {
+    let mut cc = [0.0; 128];
+    cc[7] = 100.0 / 127.0;
+    cc[10] = 0.5;
+    cc[11] = 1.0;
+    cc
+}
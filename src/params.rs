@@ -1,4 +1,5 @@
 use nih_plug::prelude::*;
+use std::sync::{Arc, Mutex};
 
 #[derive(Params)]
 pub struct SamploParams {
@@ -19,6 +20,32 @@ pub struct SamploParams {
     pub cutoff_hz: FloatParam,
     #[id = "f_res"]
     pub resonance: FloatParam,
+    /// Low-pass cutoff that brightness and pressure move when no filter is active
+    #[id = "x_cut"]
+    pub expr_cutoff_hz: FloatParam,
+
+    // Filter envelope, added to the region's `fileg_*` envelope
+    #[id = "f_env"]
+    pub fil_env_depth: FloatParam,
+    #[id = "f_att"]
+    pub fil_attack_ms: FloatParam,
+    #[id = "f_dec"]
+    pub fil_decay_ms: FloatParam,
+    #[id = "f_sus"]
+    pub fil_sustain: FloatParam,
+    #[id = "f_rel"]
+    pub fil_release_ms: FloatParam,
+
+    // Global LFO
+    #[id = "lfo_rate"]
+    pub lfo_rate: FloatParam,
+    #[id = "lfo_depth"]
+    pub lfo_depth: FloatParam,
+    #[id = "lfo_tgt"]
+    pub lfo_target: EnumParam<LfoTargetParam>,
+    /// Scale the LFO depth by the mod wheel (CC1)
+    #[id = "lfo_mw"]
+    pub lfo_mod_wheel: BoolParam,
 
     // Output
     #[id = "gain"]
@@ -31,12 +58,38 @@ pub struct SamploParams {
     pub tune_cents: FloatParam,
     #[id = "voices"]
     pub max_voices: IntParam,
+    #[id = "bend"]
+    pub bend_range: IntParam,
+
+    // MPE (lower zone: master channel 1, members from channel 2)
+    #[id = "mpe"]
+    pub mpe_enabled: BoolParam,
+    #[id = "mpe_ch"]
+    pub mpe_members: IntParam,
+    #[id = "mpe_bend"]
+    pub mpe_bend_range: IntParam,
     #[id = "vel_sens"]
     pub velocity_sens: FloatParam,
+    #[id = "vel_curve"]
+    pub velocity_curve: EnumParam<VelCurveParam>,
+    /// Custom curve output at 25%, 50% and 75% input velocity
+    #[id = "vel_pt1"]
+    pub velocity_point1: FloatParam,
+    #[id = "vel_pt2"]
+    pub velocity_point2: FloatParam,
+    #[id = "vel_pt3"]
+    pub velocity_point3: FloatParam,
 
     /// Instrument selection (idx into scanned instrument list)
     #[id = "inst"]
     pub instrument_index: IntParam,
+
+    /// Keyswitch selection (key number), -1 follows incoming MIDI keyswitches
+    #[id = "sw"]
+    pub keyswitch: IntParam,
+
+    /// `sw_label` names of the loaded instrument, shown by the keyswitch param
+    pub keyswitch_labels: Arc<Mutex<Vec<(u8, String)>>>,
 }
 
 #[derive(PartialEq, Eq, Clone, Copy, Enum)]
@@ -47,8 +100,27 @@ pub enum FilterModeParam {
     BandPass,
 }
 
+#[derive(PartialEq, Eq, Clone, Copy, Enum)]
+pub enum LfoTargetParam {
+    Pitch,
+    Filter,
+    Amp,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Enum)]
+pub enum VelCurveParam {
+    Linear,
+    Exponential,
+    Logarithmic,
+    #[name = "S-Curve"]
+    SCurve,
+    Custom,
+}
+
 impl Default for SamploParams {
     fn default() -> Self {
+        let keyswitch_labels: Arc<Mutex<Vec<(u8, String)>>> = Arc::new(Mutex::new(Vec::new()));
+
         Self {
             attack_ms: FloatParam::new(
                 "Attack",
@@ -100,6 +172,83 @@ impl Default for SamploParams {
 
             resonance: FloatParam::new("Resonance", 0.5, FloatRange::Linear { min: 0.1, max: 4.0 }),
 
+            expr_cutoff_hz: FloatParam::new(
+                "Expression Cutoff",
+                20000.0,
+                FloatRange::Skewed {
+                    min: 20.0,
+                    max: 20000.0,
+                    factor: 0.2,
+                },
+            )
+            .with_unit(" Hz"),
+
+            fil_env_depth: FloatParam::new(
+                "Filter Env",
+                0.0,
+                FloatRange::Linear {
+                    min: -9600.0,
+                    max: 9600.0,
+                },
+            )
+            .with_unit(" cents"),
+
+            fil_attack_ms: FloatParam::new(
+                "Filter Attack",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 2000.0,
+                    factor: 0.3,
+                },
+            )
+            .with_unit(" ms"),
+
+            fil_decay_ms: FloatParam::new(
+                "Filter Decay",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 4000.0,
+                    factor: 0.3,
+                },
+            )
+            .with_unit(" ms"),
+
+            fil_sustain: FloatParam::new(
+                "Filter Sustain",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            ),
+
+            fil_release_ms: FloatParam::new(
+                "Filter Release",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 8000.0,
+                    factor: 0.3,
+                },
+            )
+            .with_unit(" ms"),
+
+            lfo_rate: FloatParam::new(
+                "LFO Rate",
+                5.0,
+                FloatRange::Skewed {
+                    min: 0.05,
+                    max: 20.0,
+                    factor: 0.4,
+                },
+            )
+            .with_unit(" Hz"),
+
+            lfo_depth: FloatParam::new("LFO Depth", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
+
+            lfo_target: EnumParam::new("LFO Target", LfoTargetParam::Pitch),
+
+            lfo_mod_wheel: BoolParam::new("LFO Mod Wheel", false),
+
             gain: FloatParam::new("Gain", 0.8, FloatRange::Linear { min: 0.0, max: 2.0 })
                 .with_unit("×"),
 
@@ -124,14 +273,43 @@ impl Default for SamploParams {
 
             max_voices: IntParam::new("Voices", 32, IntRange::Linear { min: 1, max: 64 }),
 
+            bend_range: IntParam::new("Bend Range", 2, IntRange::Linear { min: 0, max: 48 })
+                .with_unit(" st"),
+
+            mpe_enabled: BoolParam::new("MPE", false),
+
+            mpe_members: IntParam::new("MPE Channels", 15, IntRange::Linear { min: 1, max: 15 }),
+
+            mpe_bend_range: IntParam::new("MPE Bend", 48, IntRange::Linear { min: 0, max: 96 })
+                .with_unit(" st"),
+
             velocity_sens: FloatParam::new(
                 "Vel Sens",
                 0.7,
                 FloatRange::Linear { min: 0.0, max: 1.0 },
             ),
 
+            velocity_curve: EnumParam::new("Vel Curve", VelCurveParam::Linear),
+
+            velocity_point1: FloatParam::new(
+                "Vel Point 1",
+                0.25,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            ),
+
+            velocity_point2: FloatParam::new(
+                "Vel Point 2",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            ),
+
+            velocity_point3: FloatParam::new(
+                "Vel Point 3",
+                0.75,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            ),
+
             instrument_index: {
-                use std::sync::Arc;
                 IntParam::new(
                     "Instrument",
                     0,
@@ -142,6 +320,25 @@ impl Default for SamploParams {
                 )
                 .with_value_to_string(Arc::new(|idx| crate::instrument_name_for_index(idx)))
             },
+
+            keyswitch: {
+                let labels = keyswitch_labels.clone();
+                IntParam::new("Articulation", -1, IntRange::Linear { min: -1, max: 127 })
+                    .with_value_to_string(Arc::new(move |key| {
+                        if key < 0 {
+                            return "MIDI".to_string();
+                        }
+                        labels
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .find(|(n, _)| *n as i32 == key)
+                            .map(|(_, label)| label.clone())
+                            .unwrap_or_else(|| key.to_string())
+                    }))
+            },
+
+            keyswitch_labels,
         }
     }
 }
@@ -156,3 +353,13 @@ impl FilterModeParam {
         }
     }
 }
+
+impl LfoTargetParam {
+    pub fn to_target(&self) -> crate::voice::LfoTarget {
+        match self {
+            LfoTargetParam::Pitch => crate::voice::LfoTarget::Pitch,
+            LfoTargetParam::Filter => crate::voice::LfoTarget::Filter,
+            LfoTargetParam::Amp => crate::voice::LfoTarget::Amp,
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use crate::dsp;
+use crate::dsp::{self, FilterMode, LfoWave};
+use crate::midi::SUSTAIN_CC;
 use serde::Deserialize;
 use smallvec::SmallVec;
 use std::sync::Arc;
@@ -8,6 +9,489 @@ pub enum LoopMode {
     NoLoop,
     Continuous,
     Sustain,
+    /// Plays to the end, ignoring note-off
+    OneShot,
+}
+
+/// Direction a loop plays in (SFZ `loop_type`)
+#[derive(Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoopType {
+    /// Jumps from the loop end back to the loop start
+    #[default]
+    Forward,
+    /// Turns around at the loop end, then jumps from the loop start back to the end
+    Backward,
+    /// Ping-pong between the loop points
+    Alternate,
+}
+
+/// How a voice is turned off when another region's group chokes it (SFZ `off_mode`)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OffMode {
+    /// Short fade
+    Fast,
+    /// Regular release stage
+    Normal,
+    /// Fade over `off_time`
+    Time,
+}
+
+/// What triggers a region (SFZ `trigger` opcode)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// Plays on note-on
+    Attack,
+    /// Plays when the note ends
+    Release,
+    /// Plays on note-on only when no other key is held
+    First,
+    /// Plays on note-on only when another key is held
+    Legato,
+    /// Plays on key-up, regardless of the sustain pedal
+    ReleaseKey,
+}
+
+impl Trigger {
+    /// Whether this trigger fires on note-on, given if another key is already held
+    #[inline]
+    pub fn fires_on_note_on(self, legato: bool) -> bool {
+        match self {
+            Trigger::Attack => true,
+            Trigger::First => !legato,
+            Trigger::Legato => legato,
+            Trigger::Release | Trigger::ReleaseKey => false,
+        }
+    }
+}
+
+/// Values for each stage of a DAHDSR envelope: times in seconds, sustain in percent
+#[derive(Clone, Copy, Default)]
+pub struct EgStages {
+    pub delay: f32,
+    pub attack: f32,
+    pub hold: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl EgStages {
+    #[inline]
+    fn add_scaled(&mut self, other: &EgStages, amount: f32) {
+        self.delay += other.delay * amount;
+        self.attack += other.attack * amount;
+        self.hold += other.hold * amount;
+        self.decay += other.decay * amount;
+        self.sustain += other.sustain * amount;
+        self.release += other.release * amount;
+    }
+}
+
+/// Per-region envelope definition (SFZ `ampeg_*`, `fileg_*`, `pitcheg_*`)
+#[derive(Clone)]
+pub struct EnvelopeDef {
+    pub stages: EgStages,
+    /// Offsets applied at full velocity (`vel2*` opcodes)
+    pub vel2: EgStages,
+    /// Offsets applied at full CC value (`*_onccN` opcodes)
+    pub cc: SmallVec<[(u8, EgStages); 2]>,
+    /// Modulation depth in cents (`depth`), unused by the amp envelope
+    pub depth: f32,
+    /// Depth added at full velocity (`vel2depth`)
+    pub vel2depth: f32,
+}
+
+impl Default for EnvelopeDef {
+    fn default() -> Self {
+        Self {
+            stages: EgStages {
+                sustain: 100.0,
+                ..EgStages::default()
+            },
+            vel2: EgStages::default(),
+            cc: SmallVec::new(),
+            depth: 0.0,
+            vel2depth: 0.0,
+        }
+    }
+}
+
+impl EnvelopeDef {
+    /// Mutable modulation amounts for a CC, added on first use
+    pub fn cc_mut(&mut self, cc: u8) -> &mut EgStages {
+        let pos = match self.cc.iter().position(|(n, _)| *n == cc) {
+            Some(pos) => pos,
+            None => {
+                self.cc.push((cc, EgStages::default()));
+                self.cc.len() - 1
+            }
+        };
+        &mut self.cc[pos].1
+    }
+
+    /// Modulation depth in cents for a velocity (0..1)
+    #[inline]
+    pub fn depth_at(&self, velocity: f32) -> f32 {
+        self.depth + self.vel2depth * velocity
+    }
+
+    /// Apply velocity (0..1) and CC (normalized) modulation at note-on
+    pub fn resolve(&self, velocity: f32, cc: &[f32; 128]) -> EgStages {
+        let mut out = self.stages;
+        out.add_scaled(&self.vel2, velocity);
+        for (num, amounts) in &self.cc {
+            out.add_scaled(amounts, cc[*num as usize & 0x7f]);
+        }
+        EgStages {
+            delay: out.delay.max(0.0),
+            attack: out.attack.max(0.0),
+            hold: out.hold.max(0.0),
+            decay: out.decay.max(0.0),
+            sustain: out.sustain.clamp(0.0, 100.0),
+            release: out.release.max(0.0),
+        }
+    }
+}
+
+/// Per-region filter (SFZ `fil_type`, `cutoff`, `resonance`, `fil_*track`)
+#[derive(Clone, Copy)]
+pub struct FilterDef {
+    pub mode: FilterMode,
+    /// Base cutoff in Hz
+    pub cutoff: f32,
+    /// Resonance in dB
+    pub resonance: f32,
+    /// Cutoff change in cents per key from `keycenter`
+    pub keytrack: f32,
+    pub keycenter: u8,
+    /// Cutoff change in cents at full velocity
+    pub veltrack: f32,
+}
+
+impl FilterDef {
+    /// Cutoff in Hz for a note and velocity (0..1)
+    pub fn cutoff_hz(&self, note: u8, velocity: f32) -> f32 {
+        let cents =
+            (note as f32 - self.keycenter as f32) * self.keytrack + velocity * self.veltrack;
+        self.cutoff * 2.0f32.powf(cents / 1200.0)
+    }
+
+    /// Resonance as filter Q: 0 dB is a flat Butterworth response
+    pub fn q(&self) -> f32 {
+        std::f32::consts::FRAC_1_SQRT_2 * dsp::db_to_linear(self.resonance.max(0.0))
+    }
+}
+
+/// Per-region LFO (SFZ v1 `amplfo_*`, `pitchlfo_*`, `fillfo_*`)
+#[derive(Clone, Default)]
+pub struct LfoDef {
+    /// Seconds before the LFO starts
+    pub delay: f32,
+    /// Fade-in time in seconds
+    pub fade: f32,
+    pub freq: f32,
+    /// Depth in dB (amplitude) or cents (pitch, filter)
+    pub depth: f32,
+    /// Depth added at full CC value (`*lfo_depthccN`)
+    pub depth_cc: SmallVec<[(u8, f32); 1]>,
+}
+
+impl LfoDef {
+    /// Depth for the current controller values (0..1)
+    #[inline]
+    pub fn depth_at(&self, cc: &[f32; 128]) -> f32 {
+        self.depth
+            + self
+                .depth_cc
+                .iter()
+                .map(|(num, depth)| depth * cc[*num as usize & 0x7f])
+                .sum::<f32>()
+    }
+}
+
+/// Destination of an SFZ v2 flex envelope or LFO
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ModTarget {
+    /// Gain scaling in percent
+    Amplitude,
+    /// Gain in dB
+    Volume,
+    /// Cents
+    Pitch,
+    /// Filter cutoff in cents
+    Cutoff,
+    /// Pan in percent
+    Pan,
+}
+
+impl ModTarget {
+    /// Parse an SFZ v2 target opcode suffix (`egN_pitch`, `lfoN_cutoff`, ...)
+    pub fn from_sfz(name: &str) -> Option<Self> {
+        match name {
+            "amplitude" => Some(ModTarget::Amplitude),
+            "volume" => Some(ModTarget::Volume),
+            "pitch" => Some(ModTarget::Pitch),
+            "cutoff" => Some(ModTarget::Cutoff),
+            "pan" => Some(ModTarget::Pan),
+            _ => None,
+        }
+    }
+}
+
+/// SFZ v2 flex envelope (`egN_timeK`, `egN_levelK`, `egN_sustain`)
+#[derive(Clone, Default)]
+pub struct FlexEgDef {
+    /// (time in seconds, level 0..1) per point, starting at point 0
+    pub points: SmallVec<[(f32, f32); 4]>,
+    /// Point held until note-off
+    pub sustain: Option<usize>,
+    pub targets: SmallVec<[(ModTarget, f32); 2]>,
+}
+
+/// SFZ v2 LFO (`lfoN_*`)
+#[derive(Clone)]
+pub struct FlexLfoDef {
+    pub freq: f32,
+    /// Seconds before the LFO starts
+    pub delay: f32,
+    /// Fade-in time in seconds
+    pub fade: f32,
+    /// Starting phase (0..1)
+    pub phase: f32,
+    pub wave: LfoWave,
+    pub targets: SmallVec<[(ModTarget, f32); 2]>,
+}
+
+impl Default for FlexLfoDef {
+    fn default() -> Self {
+        Self {
+            freq: 0.0,
+            delay: 0.0,
+            fade: 0.0,
+            phase: 0.0,
+            wave: LfoWave::Triangle,
+            targets: SmallVec::new(),
+        }
+    }
+}
+
+/// Crossfade curve (`xf_velcurve`, `xf_keycurve`, `xf_cccurve`)
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum XfCurve {
+    /// Equal power
+    #[default]
+    Power,
+    /// Linear gain
+    Gain,
+}
+
+impl XfCurve {
+    #[inline]
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            XfCurve::Power => t.sqrt(),
+            XfCurve::Gain => t,
+        }
+    }
+}
+
+/// Crossfade ranges (SFZ `xfin_*`/`xfout_*`), as (lo, hi) pairs in 0..127
+#[derive(Clone, Default)]
+pub struct CrossfadeDef {
+    pub vel_in: Option<(u8, u8)>,
+    pub vel_out: Option<(u8, u8)>,
+    pub key_in: Option<(u8, u8)>,
+    pub key_out: Option<(u8, u8)>,
+    /// (cc, lo, hi)
+    pub cc_in: SmallVec<[(u8, u8, u8); 1]>,
+    pub cc_out: SmallVec<[(u8, u8, u8); 1]>,
+    pub vel_curve: XfCurve,
+    pub key_curve: XfCurve,
+    pub cc_curve: XfCurve,
+}
+
+impl CrossfadeDef {
+    /// Gain from the key and velocity crossfades, fixed for the note
+    pub fn note_gain(&self, note: u8, velocity: u8) -> f32 {
+        let vel = velocity as f32;
+        let key = note as f32;
+        let mut gain = 1.0;
+        if let Some(range) = self.vel_in {
+            gain *= self.vel_curve.apply(fade_in(vel, range));
+        }
+        if let Some(range) = self.vel_out {
+            gain *= self.vel_curve.apply(1.0 - fade_in(vel, range));
+        }
+        if let Some(range) = self.key_in {
+            gain *= self.key_curve.apply(fade_in(key, range));
+        }
+        if let Some(range) = self.key_out {
+            gain *= self.key_curve.apply(1.0 - fade_in(key, range));
+        }
+        gain
+    }
+
+    /// Gain from the CC crossfades for the current controller values (0..1)
+    pub fn cc_gain(&self, cc: &[f32; 128]) -> f32 {
+        let value = |num: u8| cc[num as usize & 0x7f] * 127.0;
+        let fade_in_gain = self.cc_in.iter().fold(1.0, |gain, &(num, lo, hi)| {
+            gain * self.cc_curve.apply(fade_in(value(num), (lo, hi)))
+        });
+        self.cc_out
+            .iter()
+            .fold(fade_in_gain, |gain, &(num, lo, hi)| {
+                gain * self.cc_curve.apply(1.0 - fade_in(value(num), (lo, hi)))
+            })
+    }
+}
+
+/// Interpolate `amp_velcurve_N` points (sorted by velocity), with implicit end
+/// points of 0 at velocity 0 and 1 at velocity 127
+fn velcurve_gain(points: &[(u8, f32)], velocity: f32) -> f32 {
+    let mut prev = (0.0, 0.0);
+    for &(vel, gain) in points {
+        let vel = vel as f32;
+        if velocity <= vel {
+            return if vel > prev.0 {
+                dsp::lerp(prev.1, gain, (velocity - prev.0) / (vel - prev.0))
+            } else {
+                gain
+            };
+        }
+        prev = (vel, gain);
+    }
+    if prev.0 >= 127.0 {
+        prev.1
+    } else {
+        dsp::lerp(prev.1, 1.0, (velocity - prev.0) / (127.0 - prev.0))
+    }
+}
+
+/// Position of `value` in a fade-in range: 0 at or below `lo`, 1 at or above `hi`
+#[inline]
+fn fade_in(value: f32, (lo, hi): (u8, u8)) -> f32 {
+    if value <= lo as f32 {
+        0.0
+    } else if value >= hi as f32 {
+        1.0
+    } else {
+        (value - lo as f32) / (hi as f32 - lo as f32)
+    }
+}
+
+/// Per-region keyswitch conditions (SFZ `sw_*` opcodes)
+#[derive(Clone, Default)]
+pub struct KeyswitchDef {
+    /// Keyswitch key range (`sw_lokey`..`sw_hikey`)
+    pub range: Option<(u8, u8)>,
+    /// Plays only if this was the last keyswitch pressed
+    pub last: Option<u8>,
+    /// Plays only while this key is held
+    pub down: Option<u8>,
+    /// Plays only while this key is not held
+    pub up: Option<u8>,
+    /// Plays only if this was the previous note played
+    pub previous: Option<u8>,
+    /// Keyswitch active before any is pressed
+    pub default: Option<u8>,
+    /// Display name for the `last` keyswitch
+    pub label: Option<String>,
+}
+
+/// Keyswitch tracking for the loaded instrument
+pub struct KeyswitchState {
+    pub last: Option<u8>,
+    pub previous_note: Option<u8>,
+    current_note: Option<u8>,
+    pub held: [bool; 128],
+}
+
+impl KeyswitchState {
+    fn new(default: Option<u8>) -> Self {
+        Self {
+            last: default,
+            previous_note: None,
+            current_note: None,
+            held: [false; 128],
+        }
+    }
+}
+
+/// Built-in waveform played instead of the sample data (SFZ `sample=*name`)
+pub enum Generator {
+    Wavetable(Wavetable),
+    /// White noise, generated at the output rate so it ignores pitch
+    Noise,
+}
+
+/// Band-limited single-cycle waveform with one table per octave of playback rate
+pub struct Wavetable {
+    /// Table `k` only holds harmonics that stay below Nyquist when read at up to
+    /// `2^k` frames per output sample
+    tables: Vec<Vec<f32>>,
+}
+
+impl Wavetable {
+    /// Frames in one cycle
+    pub const CYCLE: usize = 2048;
+
+    /// Build the tables from the amplitude of each harmonic, normalized to a 0.8 peak
+    pub fn new(harmonic: fn(usize) -> f32) -> Self {
+        let n = Self::CYCLE;
+        let sine: Vec<f32> = (0..n)
+            .map(|i| (2.0 * core::f32::consts::PI * i as f32 / n as f32).sin())
+            .collect();
+
+        let mut tables = Vec::new();
+        // Harmonics below `limit`, halving for each octave
+        let mut limit = n / 2;
+        while limit > 1 {
+            let mut table = vec![0.0f32; n];
+            for h in 1..limit {
+                let amp = harmonic(h);
+                if amp == 0.0 {
+                    continue;
+                }
+                for (i, s) in table.iter_mut().enumerate() {
+                    *s += amp * sine[i * h % n];
+                }
+            }
+            tables.push(table);
+            limit /= 2;
+        }
+
+        // One gain for every table, so switching tables doesn't change the level
+        let peak = tables[0].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        let gain = if peak > 0.0 { 0.8 / peak } else { 0.0 };
+        for s in tables.iter_mut().flatten() {
+            *s *= gain;
+        }
+        Self { tables }
+    }
+
+    /// The table to read at `rate` frames per output sample
+    pub fn table(&self, rate: f64) -> &[f32] {
+        let level = rate.abs().max(1.0).log2().ceil() as usize;
+        &self.tables[level.min(self.tables.len() - 1)]
+    }
+
+    /// Interpolated value at a position in frames (wrapping) and playback rate
+    #[inline]
+    pub fn sample(&self, pos: f64, rate: f64) -> f32 {
+        let table = self.table(rate);
+        let n = table.len();
+        let pos = pos.rem_euclid(n as f64);
+        let idx = pos as usize;
+        let frac = (pos - idx as f64) as f32;
+        dsp::hermite_interp(
+            table[(idx + n - 1) % n],
+            table[idx % n],
+            table[(idx + 1) % n],
+            table[(idx + 2) % n],
+            frac,
+        )
+    }
 }
 
 /// A single audio sample region
@@ -20,6 +504,8 @@ pub struct Region {
     pub sample_rate: f32,
     /// Total number of frames (samples per channel)
     pub num_frames: usize,
+    /// Built-in waveform that replaces `data` when playing
+    pub generator: Option<Arc<Generator>>,
 
     // Mapping
     /// MIDI note at which sample plays at original pitch
@@ -33,18 +519,95 @@ pub struct Region {
     /// Highest velocity (0-127)
     pub hi_vel: u8,
 
+    // Playback range (in frames)
+    /// Start frame (`offset`)
+    pub offset: usize,
+    /// Random extra start frames, up to this many (`offset_random`)
+    pub offset_random: usize,
+    /// Extra start frames at full CC value (`offset_ccN`)
+    pub offset_cc: SmallVec<[(u8, usize); 1]>,
+    /// Exclusive end frame (`end` + 1, at most `num_frames`)
+    pub end: usize,
+    /// Play from `end` back toward `offset` (`direction=reverse`)
+    pub reverse: bool,
+    /// Seconds before the voice starts (`delay`)
+    pub delay: f32,
+    /// Random extra delay in seconds, up to this long (`delay_random`)
+    pub delay_random: f32,
+
     // Loop points (in frames)
     pub loop_start: Option<usize>,
     pub loop_end: Option<usize>,
     pub loop_mode: LoopMode,
+    pub loop_type: LoopType,
+    /// Times the loop repeats before playing on to the end (`loop_count`)
+    pub loop_count: Option<u32>,
+    /// Seconds blended across the loop point (`loop_crossfade`)
+    pub loop_crossfade: f32,
+
+    // Exclusive groups
+    /// Voice group (SFZ `group`)
+    pub group: u32,
+    /// Voices of this region are turned off when a region of this group starts
+    pub off_by: Option<u32>,
+    pub off_mode: OffMode,
+    /// Fade time in seconds for `OffMode::Time`
+    pub off_time: f32,
 
     // Round robin
     /// Group ID for round robin (regions with same group rotate)
     pub rr_group: u32,
     /// Sequence number within group (0, 1, 2, ...)
     pub rr_seq: u32,
+    /// Sequence length (`seq_length`): the region keeps its own counter and plays when
+    /// it reaches `rr_seq`. `None` rotates with the `rr_group` instead, over the highest `rr_seq`.
+    pub rr_length: Option<u32>,
+    /// Random selection range (`lorand`..`hirand`), 0..1
+    pub lo_rand: f32,
+    pub hi_rand: f32,
+
+    // Triggering
+    pub trigger: Trigger,
+    /// Release-trigger attenuation in dB per second the key was held
+    pub rt_decay: f32,
+
+    /// Amplitude envelope, combined with the plugin's ADSR params
+    pub amp_env: EnvelopeDef,
+    /// Velocity tracking (-1..1, `amp_veltrack`); `None` uses the plugin's velocity sensitivity
+    pub amp_veltrack: Option<f32>,
+    /// Velocity curve points (velocity, gain 0..1) from `amp_velcurve_N`
+    pub amp_velcurve: SmallVec<[(u8, f32); 4]>,
+    /// Region filter, applied before the plugin's global filter
+    pub filter: Option<FilterDef>,
+    /// Filter cutoff envelope, combined with the plugin's filter envelope params
+    pub fil_env: EnvelopeDef,
+    /// Pitch envelope, depth in cents
+    pub pitch_env: EnvelopeDef,
+    pub amp_lfo: LfoDef,
+    pub pitch_lfo: LfoDef,
+    pub fil_lfo: LfoDef,
+    /// SFZ v2 modulators, in `egN`/`lfoN` order
+    pub flex_egs: SmallVec<[FlexEgDef; 2]>,
+    pub flex_lfos: SmallVec<[FlexLfoDef; 2]>,
+
+    pub crossfade: CrossfadeDef,
+    /// CC conditions (cc, lo, hi) that must all hold at note-on (`loccN`/`hiccN`)
+    pub cc_ranges: SmallVec<[(u8, u8, u8); 2]>,
+    pub keyswitch: KeyswitchDef,
+
+    // Pitch bend range in cents, `None` uses the plugin's bend range param
+    pub bend_up: Option<f32>,
+    pub bend_down: Option<f32>,
+    /// Bend quantization in cents (0 = smooth)
+    pub bend_step: f32,
 
     // Per-region adjustments
+    /// Cents per key away from `root_note` (`pitch_keytrack`, 100 = chromatic)
+    pub pitch_keytrack: f32,
+    /// Semitones (`transpose`)
+    pub transpose: i32,
+    /// Cents added at full velocity (`pitch_veltrack`)
+    pub pitch_veltrack: f32,
     pub tune_cents: f32,
     pub volume_db: f32,
     pub volume_lin: f32,
@@ -56,6 +619,45 @@ pub struct Region {
 }
 
 impl Region {
+    /// Make the loop points safe to play: fill in a missing point, keep the loop inside
+    /// the playback range and drop empty loops. Returns what was changed, if anything.
+    pub fn validate_loop(&mut self) -> Option<String> {
+        let mut fixes = Vec::new();
+        let (start, end) = match (self.loop_start, self.loop_end) {
+            (Some(start), Some(end)) => (start, end),
+            (None, None) => {
+                if !matches!(self.loop_mode, LoopMode::Continuous | LoopMode::Sustain) {
+                    return None;
+                }
+                // A loop mode without points loops the whole sample
+                (self.offset, self.end)
+            }
+            (Some(start), None) => {
+                fixes.push(format!("loop end set to frame {}", self.end));
+                (start, self.end)
+            }
+            (None, Some(end)) => {
+                fixes.push(format!("loop start set to frame {}", self.offset));
+                (self.offset, end)
+            }
+        };
+
+        let clamped = end.min(self.end);
+        if clamped != end {
+            fixes.push(format!("loop end {} clamped to {}", end, clamped));
+        }
+        if start >= clamped {
+            fixes.push(format!("empty loop {}..{} removed", start, clamped));
+            self.loop_start = None;
+            self.loop_end = None;
+        } else {
+            self.loop_start = Some(start);
+            self.loop_end = Some(clamped);
+        }
+
+        (!fixes.is_empty()).then(|| fixes.join(", "))
+    }
+
     /// Check if this region matches a given note, velocity, and round robin sequence
     #[inline]
     pub fn matches(&self, note: u8, velocity: u8, rr_seq: Option<u32>) -> bool {
@@ -75,11 +677,85 @@ impl Region {
             && velocity <= self.hi_vel
     }
 
-    /// Calculate playback rate for a given note at a target sample rate
+    /// Check the random selection range; `hirand` is exclusive except at 1
+    #[inline]
+    pub fn matches_rand(&self, random: f32) -> bool {
+        random >= self.lo_rand && (random < self.hi_rand || self.hi_rand >= 1.0)
+    }
+
+    /// Check that every CC condition holds for the given controller values (0..1)
+    #[inline]
+    pub fn matches_cc(&self, cc: &[f32; 128]) -> bool {
+        self.cc_ranges.iter().all(|&(num, lo, hi)| {
+            let value = (cc[num as usize & 0x7f] * 127.0).round() as u8;
+            value >= lo && value <= hi
+        })
+    }
+
+    /// Check the keyswitch conditions against the instrument's keyswitch state
+    #[inline]
+    pub fn matches_keyswitch(&self, sw: &KeyswitchState) -> bool {
+        let ks = &self.keyswitch;
+        ks.last.is_none_or(|n| sw.last == Some(n))
+            && ks.down.is_none_or(|n| sw.held[n as usize & 0x7f])
+            && ks.up.is_none_or(|n| !sw.held[n as usize & 0x7f])
+            && ks.previous.is_none_or(|n| sw.previous_note == Some(n))
+    }
+
+    /// Amplitude for a velocity (0..1). Negative tracking makes soft notes louder.
+    pub fn amp_velocity(&self, velocity: f32, default_veltrack: f32) -> f32 {
+        let curve = if self.amp_velcurve.is_empty() {
+            velocity
+        } else {
+            velcurve_gain(&self.amp_velcurve, velocity * 127.0)
+        };
+        let track = self.amp_veltrack.unwrap_or(default_veltrack);
+        if track >= 0.0 {
+            1.0 - track + track * curve
+        } else {
+            1.0 + track * curve
+        }
+    }
+
+    /// Start frame for the current controller values (0..1) and a random value (0..1).
+    /// Reversed regions start that far before the end instead.
+    pub fn start_frame(&self, cc: &[f32; 128], random: f32) -> usize {
+        let cc_offset: f32 = self
+            .offset_cc
+            .iter()
+            .map(|&(num, frames)| frames as f32 * cc[num as usize & 0x7f])
+            .sum();
+        let extra = cc_offset as usize + (self.offset_random as f32 * random) as usize;
+        if self.reverse {
+            self.end.saturating_sub(1 + extra).max(self.offset)
+        } else {
+            (self.offset + extra).min(self.end)
+        }
+    }
+
+    /// Pitch bend offset in cents for a bend position (-1..1), given the default range in cents
+    #[inline]
+    pub fn bend_cents(&self, bend: f32, default_range: f32) -> f32 {
+        let cents = if bend >= 0.0 {
+            bend * self.bend_up.unwrap_or(default_range)
+        } else {
+            -bend * self.bend_down.unwrap_or(-default_range)
+        };
+        if self.bend_step >= 1.0 {
+            (cents / self.bend_step).round() * self.bend_step
+        } else {
+            cents
+        }
+    }
+
+    /// Calculate playback rate for a given note and velocity (0..1) at a target sample rate
     #[inline]
-    pub fn playback_rate(&self, note: u8, target_sr: f32) -> f64 {
-        let semitone_diff = (note as f32 - self.root_note as f32) + (self.tune_cents / 100.0);
-        let pitch_ratio = 2.0f64.powf(semitone_diff as f64 / 12.0);
+    pub fn playback_rate(&self, note: u8, velocity: f32, target_sr: f32) -> f64 {
+        let cents = (note as f32 - self.root_note as f32) * self.pitch_keytrack
+            + self.transpose as f32 * 100.0
+            + self.pitch_veltrack * velocity
+            + self.tune_cents;
+        let pitch_ratio = 2.0f64.powf(cents as f64 / 1200.0);
         let sr_ratio = self.sample_rate as f64 / target_sr as f64;
         pitch_ratio * sr_ratio
     }
@@ -91,9 +767,7 @@ impl Region {
         let frac = (pos - idx as f64) as f32;
 
         if self.channels == 1 {
-            let m = self.interpolate_mono(idx, frac);
-            let (gl, gr) = dsp::pan_to_gains(self.pan);
-            (m * gl, m * gr)
+            self.mono_to_stereo(self.interpolate_mono(idx, frac))
         } else {
             let l = self.interpolate_channel(idx, frac, 0);
             let r = self.interpolate_channel(idx, frac, 1);
@@ -101,6 +775,40 @@ impl Region {
         }
     }
 
+    /// Spread a mono sample with the region's pan
+    #[inline]
+    pub fn mono_to_stereo(&self, m: f32) -> (f32, f32) {
+        let (gl, gr) = dsp::pan_to_gains(self.pan);
+        (m * gl, m * gr)
+    }
+
+    /// Get stereo samples inside a loop, blending in the material from the other side of
+    /// the loop point as a jump nears (`loop_crossfade`). Ping-pong loops never jump.
+    pub fn get_sample_looped(&self, pos: f64, start: f64, end: f64, backward: bool) -> (f32, f32) {
+        let sample = self.get_sample_stereo(pos);
+        let len = end - start;
+        let xf = (self.loop_crossfade as f64 * self.sample_rate as f64).min(len);
+        let (t, other) = match (self.loop_type, backward) {
+            (LoopType::Alternate, _) | (LoopType::Backward, false) => return sample,
+            (LoopType::Forward, false) => {
+                let xf = xf.min(start);
+                ((pos - (end - xf)) / xf, pos - len)
+            }
+            (_, true) => {
+                // Only audio up to the region's end can be faded in
+                let xf = xf.min(self.end as f64 - end);
+                ((start + xf - pos) / xf, pos + len)
+            }
+        };
+        // NaN when there is no room to fade
+        if t.is_nan() || t <= 0.0 {
+            return sample;
+        }
+        let t = t.min(1.0) as f32;
+        let (ol, or) = self.get_sample_stereo(other);
+        (dsp::lerp(sample.0, ol, t), dsp::lerp(sample.1, or, t))
+    }
+
     #[inline]
     fn interpolate_mono(&self, idx: usize, frac: f32) -> f32 {
         let n = self.num_frames;
@@ -146,32 +854,40 @@ impl Region {
 /// Round robin state tracker
 #[derive(Default)]
 pub struct RoundRobinState {
-    /// Per-group per-note sequence counters: group -> [note_0..note_127]
-    state: std::collections::HashMap<u32, Box<[u32; 128]>>,
+    /// Sequence counters keyed on the set of regions they cycle through
+    state: std::collections::HashMap<u64, u32>,
+    /// Per-region counters for SFZ `seq_length`
+    regions: std::collections::HashMap<usize, u32>,
 }
 
 impl RoundRobinState {
     pub fn new() -> Self {
         Self {
             state: std::collections::HashMap::new(),
+            regions: std::collections::HashMap::new(),
         }
     }
 
-    /// Get and advance the round robin counter for a note/group
-    pub fn next(&mut self, note: u8, group: u32, max_seq: u32) -> u32 {
-        let arr = self
-            .state
-            .entry(group)
-            .or_insert_with(|| Box::new([0u32; 128]));
-        let current = &mut arr[note as usize];
-        let seq = *current;
-        *current = (seq + 1) % (max_seq + 1);
+    /// Get and advance the round robin counter for a region set
+    pub fn next(&mut self, key: u64, length: u32) -> u32 {
+        let current = self.state.entry(key).or_insert(0);
+        let seq = *current % length.max(1);
+        *current = (seq + 1) % length.max(1);
+        seq
+    }
+
+    /// Get and advance a single region's counter
+    pub fn next_region(&mut self, region: usize, length: u32) -> u32 {
+        let current = self.regions.entry(region).or_insert(0);
+        let seq = *current % length.max(1);
+        *current = (seq + 1) % length.max(1);
         seq
     }
 
     /// Reset all counters
     pub fn reset(&mut self) {
         self.state.clear();
+        self.regions.clear();
     }
 }
 
@@ -179,8 +895,19 @@ impl RoundRobinState {
 pub struct Instrument {
     pub name: String,
     pub regions: Vec<Region>,
-    /// Per-group rr_max: group -> [note*4 + vel_layer] -> max_seq
-    rr_max: std::collections::HashMap<u32, Box<[u32; 512]>>,
+    /// Keys inside a declared keyswitch range (`sw_lokey`..`sw_hikey`), which do not sound
+    keyswitch_keys: [bool; 128],
+    /// Keys that select a `sw_last` articulation
+    switch_keys: [bool; 128],
+    sw_default: Option<u8>,
+    /// Keyswitch names (`sw_label`) by key
+    pub sw_labels: Vec<(u8, String)>,
+    pub sw: KeyswitchState,
+
+    /// Sustain pedal controller (`sustain_cc`)
+    pub sustain_cc: u8,
+    /// Pedal is down at or above this CC value (`sustain_lo`, 0..127)
+    pub sustain_lo: f32,
 }
 
 impl Instrument {
@@ -188,95 +915,187 @@ impl Instrument {
         Self {
             name: String::from("Empty"),
             regions: Vec::new(),
-            rr_max: std::collections::HashMap::new(),
+            keyswitch_keys: [false; 128],
+            switch_keys: [false; 128],
+            sw_default: None,
+            sw_labels: Vec::new(),
+            sw: KeyswitchState::new(None),
+            sustain_cc: SUSTAIN_CC,
+            sustain_lo: 64.0,
         }
     }
 
     pub fn new(name: String, regions: Vec<Region>) -> Self {
+        let sw_default = regions.iter().find_map(|r| r.keyswitch.default);
         let mut inst = Self {
             name,
             regions,
-            rr_max: std::collections::HashMap::new(),
+            keyswitch_keys: [false; 128],
+            switch_keys: [false; 128],
+            sw_default,
+            sw_labels: Vec::new(),
+            sw: KeyswitchState::new(sw_default),
+            sustain_cc: SUSTAIN_CC,
+            sustain_lo: 64.0,
         };
-        inst.build_rr_map();
+        inst.build_keyswitch_map();
         inst
     }
 
-    /// Build the round robin max sequence map
-    fn build_rr_map(&mut self) {
-        self.rr_max.clear();
-
+    /// Collect keyswitch keys and labels from the regions
+    fn build_keyswitch_map(&mut self) {
         for region in &self.regions {
-            for note in region.lo_note..=region.hi_note {
-                let vel_layer = (region.lo_vel / 32).min(3);
-                let idx = (note as usize) * 4 + vel_layer as usize;
-
-                let arr = self
-                    .rr_max
-                    .entry(region.rr_group)
-                    .or_insert_with(|| Box::new([0u32; 512]));
-                arr[idx] = arr[idx].max(region.rr_seq);
+            let ks = &region.keyswitch;
+            if let Some((lo, hi)) = ks.range {
+                for note in lo..=hi.min(127) {
+                    self.keyswitch_keys[note as usize] = true;
+                }
+            }
+            // Without a declared range, switch keys still sound if regions map them
+            if let Some(note) = ks.last {
+                self.switch_keys[note as usize & 0x7f] = true;
             }
+            if let (Some(note), Some(label)) = (ks.last, &ks.label)
+                && !self.sw_labels.iter().any(|(n, _)| *n == note)
+            {
+                self.sw_labels.push((note, label.clone()));
+            }
+        }
+        self.sw_labels.sort_by_key(|(n, _)| *n);
+    }
+
+    /// Track a note-on for keyswitching. Returns true if the note is inside the
+    /// keyswitch range and should not sound.
+    pub fn keyswitch_down(&mut self, note: u8) -> bool {
+        let note = note & 0x7f;
+        self.sw.held[note as usize] = true;
+        if self.switch_keys[note as usize] {
+            self.sw.last = Some(note);
+        }
+        if self.keyswitch_keys[note as usize] {
+            return true;
         }
+        self.sw.previous_note = self.sw.current_note;
+        self.sw.current_note = Some(note);
+        false
+    }
+
+    pub fn keyswitch_up(&mut self, note: u8) {
+        self.sw.held[note as usize & 0x7f] = false;
     }
 
-    /// Get max round robin sequence for a note/group
-    pub fn get_rr_max(&self, note: u8, velocity: u8, group: u32) -> u32 {
-        let vel_layer = (velocity / 32).min(3);
-        let idx = (note as usize) * 4 + vel_layer as usize;
-        self.rr_max.get(&group).map(|arr| arr[idx]).unwrap_or(0)
+    /// Select a keyswitch directly (e.g. from the plugin param)
+    pub fn select_keyswitch(&mut self, note: u8) {
+        self.sw.last = Some(note & 0x7f);
     }
 
-    /// Find the best matching region for a note/velocity, with round robin
-    pub fn find_region(
+    pub fn reset_keyswitches(&mut self) {
+        self.sw = KeyswitchState::new(self.sw_default);
+    }
+
+    /// Find the note-on regions to layer for a note/velocity. `legato` tells whether
+    /// another key was already held (for `first`/`legato` triggers), `cc` holds the
+    /// channel's current controller values and `random` (0..1) picks among
+    /// `lorand`/`hirand` regions. Regions with a `seq_length` each follow their own
+    /// counter; the others cycle per group over the regions that match this note.
+    pub fn find_regions(
         &self,
         note: u8,
         velocity: u8,
+        legato: bool,
+        cc: &[f32; 128],
+        random: f32,
         rr_state: &mut RoundRobinState,
-    ) -> Option<usize> {
-        // First pass: find all matching regions and determine groups
-        let mut matches: SmallVec<[(usize, u32, u32); 8]> = SmallVec::new(); // (index, group, seq)
+    ) -> SmallVec<[usize; 8]> {
+        let candidates: SmallVec<[usize; 8]> = self
+            .regions
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| {
+                r.trigger.fires_on_note_on(legato)
+                    && r.matches_base(note, velocity)
+                    && r.crossfade.note_gain(note, velocity) > 0.0
+                    && r.matches_cc(cc)
+                    && r.matches_keyswitch(&self.sw)
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        let mut selected: SmallVec<[usize; 8]> = SmallVec::new();
+        let mut groups: SmallVec<[u32; 4]> = SmallVec::new();
+        for &idx in &candidates {
+            let region = &self.regions[idx];
 
-        for (i, region) in self.regions.iter().enumerate() {
-            if region.matches_base(note, velocity) {
-                matches.push((i, region.rr_group, region.rr_seq));
+            // SFZ `seq_length`: each region counts its own matches
+            if let Some(length) = region.rr_length {
+                let seq = rr_state.next_region(idx, length);
+                if seq == region.rr_seq && region.matches_rand(random) {
+                    selected.push(idx);
+                }
+                continue;
             }
-        }
 
-        if matches.is_empty() {
-            return None;
-        }
+            let group = region.rr_group;
+            if groups.contains(&group) {
+                continue;
+            }
+            groups.push(group);
 
-        // If only one match, return it
-        if matches.len() == 1 {
-            return Some(matches[0].0);
+            let set: SmallVec<[usize; 8]> = candidates
+                .iter()
+                .copied()
+                .filter(|&i| {
+                    let r = &self.regions[i];
+                    r.rr_length.is_none() && r.rr_group == group
+                })
+                .collect();
+            let length = set
+                .iter()
+                .map(|&i| self.regions[i].rr_seq)
+                .max()
+                .unwrap_or(0)
+                + 1;
+            let seq = rr_state.next(region_set_key(group, &set), length);
+            selected.extend(set.iter().copied().filter(|&i| {
+                self.regions[i].rr_seq == seq && self.regions[i].matches_rand(random)
+            }));
         }
 
-        // Group by rr_group and select based on round robin
-        // For simplicity, take the first group we encounter
-        let group = matches[0].1;
-        let max_seq = self.get_rr_max(note, velocity, group);
-        let target_seq = rr_state.next(note, group, max_seq);
-
-        // Find region with matching sequence, or fall back to first
-        matches
-            .iter()
-            .find(|(_, g, s)| *g == group && *s == target_seq)
-            .or_else(|| matches.first())
-            .map(|(i, _, _)| *i)
+        selected.sort_unstable();
+        selected
     }
 
-    /// Find all matching regions (for layering without round robin)
-    pub fn find_all_regions(&self, note: u8, velocity: u8) -> Vec<usize> {
+    /// Find all regions with a release-type `trigger` for a note and its original velocity
+    pub fn find_release_regions(
+        &self,
+        note: u8,
+        velocity: u8,
+        trigger: Trigger,
+        cc: &[f32; 128],
+    ) -> SmallVec<[usize; 4]> {
         self.regions
             .iter()
             .enumerate()
-            .filter(|(_, r)| r.matches_base(note, velocity))
+            .filter(|(_, r)| {
+                r.trigger == trigger
+                    && r.matches_base(note, velocity)
+                    && r.matches_cc(cc)
+                    && r.matches_keyswitch(&self.sw)
+            })
             .map(|(i, _)| i)
             .collect()
     }
 }
 
+/// Round robin counter key for a group's set of matching regions
+fn region_set_key(group: u32, regions: &[usize]) -> u64 {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    group.hash(&mut hasher);
+    regions.hash(&mut hasher);
+    hasher.finish()
+}
+
 /// JSON definition format
 #[derive(Deserialize)]
 pub struct InstrumentDef {
@@ -288,8 +1107,9 @@ pub struct InstrumentDef {
 #[derive(Deserialize)]
 pub struct RegionDef {
     pub sample: String,
-    #[serde(default = "default_root")]
-    pub root: u8,
+    /// Defaults to the sample's root note, or 60
+    #[serde(default)]
+    pub root: Option<u8>,
     #[serde(default)]
     pub lo_note: Option<u8>,
     #[serde(default)]
@@ -302,20 +1122,371 @@ pub struct RegionDef {
     pub loop_start: Option<usize>,
     #[serde(default)]
     pub loop_end: Option<usize>,
+    /// Defaults to on when the sample has a loop
     #[serde(default)]
-    pub loop_enabled: bool,
+    pub loop_enabled: Option<bool>,
+    /// `forward`, `backward` or `alternate`
+    #[serde(default)]
+    pub loop_type: Option<LoopType>,
+    /// Seconds blended across the loop point
+    #[serde(default)]
+    pub loop_crossfade: f32,
     #[serde(default)]
     pub rr_group: u32,
     #[serde(default)]
     pub rr_seq: u32,
     #[serde(default)]
-    pub tune_cents: f32,
+    pub tune_cents: Option<f32>,
     #[serde(default)]
     pub volume_db: f32,
     #[serde(default)]
     pub pan: f32,
 }
 
-fn default_root() -> u8 {
-    60
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region() -> Region {
+        crate::loader::create_test_instrument().regions.remove(0)
+    }
+
+    #[test]
+    fn validate_loop_fills_a_missing_point() {
+        let mut r = region();
+        r.offset = 10;
+        r.loop_start = None;
+        r.loop_end = Some(500);
+        assert!(r.validate_loop().is_some());
+        assert_eq!((r.loop_start, r.loop_end), (Some(10), Some(500)));
+
+        r.loop_start = Some(100);
+        r.loop_end = None;
+        assert!(r.validate_loop().is_some());
+        assert_eq!((r.loop_start, r.loop_end), (Some(100), Some(r.end)));
+    }
+
+    #[test]
+    fn validate_loop_clamps_and_drops_empty_loops() {
+        let mut r = region();
+        r.loop_start = Some(100);
+        r.loop_end = Some(r.end + 50);
+        assert!(r.validate_loop().is_some());
+        assert_eq!(r.loop_end, Some(r.end));
+        // Already valid: nothing to report
+        assert_eq!(r.validate_loop(), None);
+
+        r.loop_start = Some(300);
+        r.loop_end = Some(300);
+        assert!(r.validate_loop().is_some());
+        assert_eq!((r.loop_start, r.loop_end), (None, None));
+    }
+
+    #[test]
+    fn validate_loop_without_points_loops_the_playback_range() {
+        let mut r = region();
+        r.loop_start = None;
+        r.loop_end = None;
+        r.loop_mode = LoopMode::NoLoop;
+        assert_eq!(r.validate_loop(), None);
+        assert_eq!((r.loop_start, r.loop_end), (None, None));
+
+        r.loop_mode = LoopMode::Sustain;
+        r.offset = 4;
+        assert_eq!(r.validate_loop(), None);
+        assert_eq!((r.loop_start, r.loop_end), (Some(4), Some(r.end)));
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn velocity_and_key_crossfades() {
+        let xf = CrossfadeDef {
+            vel_in: Some((20, 60)),
+            key_out: Some((60, 72)),
+            vel_curve: XfCurve::Gain,
+            ..CrossfadeDef::default()
+        };
+        assert_eq!(xf.note_gain(48, 10), 0.0);
+        assert!(close(xf.note_gain(48, 40), 0.5));
+        assert_eq!(xf.note_gain(48, 100), 1.0);
+        // Equal power on the key fade
+        assert!(close(xf.note_gain(66, 100), 0.5f32.sqrt()));
+        assert_eq!(xf.note_gain(80, 100), 0.0);
+        assert_eq!(CrossfadeDef::default().note_gain(0, 0), 1.0);
+    }
+
+    #[test]
+    fn cc_crossfades_follow_each_controller() {
+        let xf = CrossfadeDef {
+            cc_in: SmallVec::from_slice(&[(1, 0, 127)]),
+            cc_out: SmallVec::from_slice(&[(2, 0, 127)]),
+            cc_curve: XfCurve::Gain,
+            ..CrossfadeDef::default()
+        };
+        let mut cc = [0.0; 128];
+        assert_eq!(xf.cc_gain(&cc), 0.0);
+        cc[1] = 1.0;
+        assert_eq!(xf.cc_gain(&cc), 1.0);
+        cc[2] = 0.5;
+        assert!(close(xf.cc_gain(&cc), 0.5));
+    }
+
+    #[test]
+    fn velcurve_interpolates_between_points() {
+        let points = [(64, 0.8)];
+        assert_eq!(velcurve_gain(&points, 0.0), 0.0);
+        assert!(close(velcurve_gain(&points, 32.0), 0.4));
+        assert!(close(velcurve_gain(&points, 64.0), 0.8));
+        assert!(close(velcurve_gain(&points, 127.0), 1.0));
+
+        // Explicit end points replace the implicit ones
+        let points = [(0, 0.5), (127, 0.5)];
+        assert_eq!(velcurve_gain(&points, 0.0), 0.5);
+        assert_eq!(velcurve_gain(&points, 127.0), 0.5);
+    }
+
+    #[test]
+    fn amp_velocity_applies_the_curve_and_tracking() {
+        let mut r = region();
+        r.amp_velcurve = SmallVec::from_slice(&[(127, 0.25)]);
+        r.amp_veltrack = Some(1.0);
+        assert!(close(r.amp_velocity(1.0, 1.0), 0.25));
+        r.amp_veltrack = Some(0.5);
+        assert!(close(r.amp_velocity(1.0, 1.0), 0.625));
+        // Negative tracking makes the curve's loud end quieter
+        r.amp_veltrack = Some(-1.0);
+        assert!(close(r.amp_velocity(1.0, 1.0), 0.75));
+    }
+
+    #[test]
+    fn bend_uses_separate_up_and_down_ranges() {
+        let mut r = region();
+        assert_eq!(r.bend_cents(1.0, 200.0), 200.0);
+        assert_eq!(r.bend_cents(-1.0, 200.0), -200.0);
+
+        r.bend_up = Some(1200.0);
+        r.bend_down = Some(-100.0);
+        assert_eq!(r.bend_cents(0.5, 200.0), 600.0);
+        assert_eq!(r.bend_cents(-0.5, 200.0), -50.0);
+    }
+
+    #[test]
+    fn bend_step_quantizes() {
+        let mut r = region();
+        r.bend_up = Some(1200.0);
+        r.bend_step = 100.0;
+        assert_eq!(r.bend_cents(0.3, 200.0), 400.0);
+        assert_eq!(r.bend_cents(-0.3, 200.0), -100.0);
+    }
+
+    #[test]
+    fn start_frame_adds_cc_and_random_offsets() {
+        let mut r = region();
+        r.offset = 100;
+        r.offset_cc = SmallVec::from_slice(&[(1, 200)]);
+        r.offset_random = 50;
+        let mut cc = [0.0; 128];
+        assert_eq!(r.start_frame(&cc, 0.0), 100);
+        cc[1] = 0.5;
+        assert_eq!(r.start_frame(&cc, 0.0), 200);
+        assert_eq!(r.start_frame(&cc, 1.0), 250);
+
+        // Never past the end
+        r.offset_cc = SmallVec::from_slice(&[(1, 100_000)]);
+        assert_eq!(r.start_frame(&cc, 0.0), r.end);
+    }
+
+    #[test]
+    fn reversed_start_frame_counts_from_the_end() {
+        let mut r = region();
+        r.reverse = true;
+        r.offset = 100;
+        r.offset_random = 50;
+        let cc = [0.0; 128];
+        assert_eq!(r.start_frame(&cc, 0.0), r.end - 1);
+        assert_eq!(r.start_frame(&cc, 1.0), r.end - 51);
+
+        // Not before the region's offset
+        r.offset_random = 100_000;
+        assert_eq!(r.start_frame(&cc, 1.0), 100);
+    }
+
+    #[test]
+    fn backward_loop_crossfade_stays_inside_the_region() {
+        let mut r = region();
+        r.end = 1000;
+        r.loop_type = LoopType::Backward;
+        r.loop_crossfade = 1.0;
+        let plain = r.get_sample_stereo(510.0);
+        assert_eq!(r.get_sample_looped(510.0, 500.0, 1000.0, true), plain);
+
+        // With room past the loop end, the fade reads it
+        r.end = r.num_frames;
+        assert_ne!(r.get_sample_looped(510.0, 500.0, 1000.0, true), plain);
+    }
+
+    #[test]
+    fn switch_keys_outside_range_still_sound() {
+        let mut r = region();
+        r.keyswitch.last = Some(36);
+        let mut inst = Instrument::new(String::from("ks"), vec![r]);
+        assert!(!inst.keyswitch_down(36));
+        assert_eq!(inst.sw.last, Some(36));
+    }
+
+    #[test]
+    fn keys_in_declared_range_are_silent() {
+        let mut r = region();
+        r.keyswitch.range = Some((36, 40));
+        r.keyswitch.last = Some(36);
+        let mut inst = Instrument::new(String::from("ks"), vec![r]);
+        assert!(inst.keyswitch_down(38));
+        assert!(inst.keyswitch_down(36));
+        assert_eq!(inst.sw.last, Some(36));
+        assert!(!inst.keyswitch_down(60));
+    }
+
+    fn find(inst: &Instrument, random: f32, rr: &mut RoundRobinState) -> Vec<usize> {
+        inst.find_regions(60, 100, false, &[0.0; 128], random, rr)
+            .to_vec()
+    }
+
+    #[test]
+    fn round_robin_layer_plays_with_unsequenced_layer() {
+        // A body layer stacked with a three-way round robin
+        let body = region();
+        let rr: Vec<Region> = (0..3)
+            .map(|seq| {
+                let mut r = region();
+                r.rr_seq = seq;
+                r.rr_length = Some(3);
+                r
+            })
+            .collect();
+        let inst = Instrument::new(
+            String::from("rr"),
+            std::iter::once(body).chain(rr).collect(),
+        );
+        let mut state = RoundRobinState::new();
+
+        for step in 0..6 {
+            assert_eq!(find(&inst, 0.0, &mut state), vec![0, 1 + step % 3]);
+        }
+    }
+
+    #[test]
+    fn sequenced_regions_keep_their_own_counters() {
+        // Two round robins of different lengths on the same key
+        let lengths = [(0, 2), (1, 2), (0, 3), (1, 3), (2, 3)];
+        let regions = lengths
+            .iter()
+            .map(|&(seq, length)| {
+                let mut r = region();
+                r.rr_seq = seq;
+                r.rr_length = Some(length);
+                r
+            })
+            .collect();
+        let inst = Instrument::new(String::from("rr"), regions);
+        let mut state = RoundRobinState::new();
+
+        assert_eq!(find(&inst, 0.0, &mut state), vec![0, 2]);
+        assert_eq!(find(&inst, 0.0, &mut state), vec![1, 3]);
+        assert_eq!(find(&inst, 0.0, &mut state), vec![0, 4]);
+        assert_eq!(find(&inst, 0.0, &mut state), vec![1, 2]);
+    }
+
+    #[test]
+    fn random_ranges_pick_one_region() {
+        let ranges = [(0.0, 0.5), (0.5, 1.0)];
+        let regions = ranges
+            .iter()
+            .map(|&(lo, hi)| {
+                let mut r = region();
+                r.lo_rand = lo;
+                r.hi_rand = hi;
+                r
+            })
+            .collect();
+        let inst = Instrument::new(String::from("rand"), regions);
+        let mut state = RoundRobinState::new();
+
+        assert_eq!(find(&inst, 0.0, &mut state), vec![0]);
+        assert_eq!(find(&inst, 0.49, &mut state), vec![0]);
+        assert_eq!(find(&inst, 0.5, &mut state), vec![1]);
+        assert_eq!(find(&inst, 1.0, &mut state), vec![1]);
+    }
+
+    #[test]
+    fn group_round_robin_skips_missing_positions() {
+        // JSON-style groups: no region at position 1 means nothing plays there
+        let regions = [0, 2]
+            .iter()
+            .map(|&seq| {
+                let mut r = region();
+                r.rr_group = 1;
+                r.rr_seq = seq;
+                r
+            })
+            .collect();
+        let inst = Instrument::new(String::from("rr"), regions);
+        let mut state = RoundRobinState::new();
+
+        assert_eq!(find(&inst, 0.0, &mut state), vec![0]);
+        assert!(find(&inst, 0.0, &mut state).is_empty());
+        assert_eq!(find(&inst, 0.0, &mut state), vec![1]);
+    }
+
+    /// Magnitude of harmonic `h` in one cycle
+    fn harmonic_level(table: &[f32], h: usize) -> f32 {
+        let n = table.len() as f64;
+        let (mut re, mut im) = (0.0f64, 0.0f64);
+        for (i, &s) in table.iter().enumerate() {
+            let phase = 2.0 * std::f64::consts::PI * (h * i) as f64 / n;
+            re += s as f64 * phase.cos();
+            im += s as f64 * phase.sin();
+        }
+        (2.0 * (re * re + im * im).sqrt() / n) as f32
+    }
+
+    #[test]
+    fn wavetables_stay_below_nyquist_up_to_the_top_note() {
+        let saw = Wavetable::new(|h| 1.0 / h as f32);
+        let out_sr = 48000.0;
+        for note in [60u8, 84, 100, 120, 127] {
+            let hz = 440.0 * 2.0f64.powf((note as f64 - 69.0) / 12.0);
+            // Frames per output sample when one cycle is `CYCLE` frames
+            let rate = hz * Wavetable::CYCLE as f64 / out_sr;
+            let table = saw.table(rate);
+            assert!(harmonic_level(table, 1) > 0.1, "note {note}");
+            let first_alias = (out_sr / 2.0 / hz).ceil() as usize;
+            for h in first_alias..Wavetable::CYCLE / 2 {
+                assert!(harmonic_level(table, h) < 1e-4, "note {note} harmonic {h}");
+            }
+            // The highest harmonic an octave below Nyquist is kept
+            let kept = (out_sr / 4.0 / hz).ceil() as usize - 1;
+            if kept > 1 {
+                assert!(
+                    harmonic_level(table, kept) > 1e-4,
+                    "note {note} harmonic {kept}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn wavetable_reads_wrap_around_the_cycle() {
+        let sine = Wavetable::new(|h| if h == 1 { 1.0 } else { 0.0 });
+        let n = Wavetable::CYCLE as f64;
+        for pos in [0.0, 0.5, n - 0.5, n + 3.25] {
+            let expected = 0.8 * (2.0 * std::f64::consts::PI * pos / n).sin();
+            assert!(
+                (sine.sample(pos, 1.0) as f64 - expected).abs() < 1e-3,
+                "pos {pos}"
+            );
+        }
+    }
 }
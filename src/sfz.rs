@@ -1,8 +1,13 @@
 //! Basic SFZ parser - supports common opcodes needed for most instruments
 //!
 
-use crate::loader::load_audio;
-use crate::sample::{Instrument, Region};
+use crate::dsp::{FilterMode, LfoWave};
+use crate::loader::{generate_audio, load_audio};
+use crate::sample::{
+    CrossfadeDef, EgStages, EnvelopeDef, FlexEgDef, FlexLfoDef, Instrument, KeyswitchDef, LfoDef,
+    ModTarget, Region, XfCurve,
+};
+use smallvec::SmallVec;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -18,18 +23,31 @@ enum Section {
     Region,
 }
 
+/// Root note of a region (`pitch_keycenter`)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum KeyCenter {
+    Note(u8),
+    /// `pitch_keycenter=sample`: the root note stored in the file
+    FromSample,
+}
+
 /// Accumulated opcode values (inherited from control -> global -> group -> region)
 #[derive(Clone, Default)]
 struct OpcodeSet {
     // Sample
     sample: Option<String>,
     offset: Option<usize>,
-    end: Option<usize>,
+    offset_random: Option<usize>,
+    offset_cc: HashMap<u8, usize>,
+    /// Last frame to play; negative disables the region
+    end: Option<i64>,
+    delay: Option<f32>,
+    delay_random: Option<f32>,
 
     // Key mapping
     lokey: Option<u8>,
     hikey: Option<u8>,
-    pitch_keycenter: Option<u8>,
+    pitch_keycenter: Option<KeyCenter>,
     key: Option<u8>,
 
     // Velocity mapping
@@ -38,27 +56,93 @@ struct OpcodeSet {
 
     // Loop
     loop_mode: Option<String>,
+    direction: Option<String>,
     loop_start: Option<usize>,
     loop_end: Option<usize>,
+    loop_type: Option<String>,
+    loop_count: Option<u32>,
+    loop_crossfade: Option<f32>,
 
     // Tuning/volume
+    pitch_keytrack: Option<f32>,
+    transpose: Option<i32>,
+    pitch_veltrack: Option<f32>,
     tune: Option<f32>,
     volume: Option<f32>,
     pan: Option<f32>,
 
+    // Pitch bend (cents)
+    bend_up: Option<f32>,
+    bend_down: Option<f32>,
+    bend_step: Option<f32>,
+
+    // Filter
+    fil_type: Option<String>,
+    cutoff: Option<f32>,
+    resonance: Option<f32>,
+    fil_keytrack: Option<f32>,
+    fil_keycenter: Option<u8>,
+    fil_veltrack: Option<f32>,
+
     // Round robin
     seq_length: Option<u32>,
     seq_position: Option<u32>,
+    lorand: Option<f32>,
+    hirand: Option<f32>,
 
     // Voice groups
     group: Option<u32>,
+    off_by: Option<u32>,
+    off_mode: Option<String>,
+    off_time: Option<f32>,
+
+    // Triggering
+    trigger: Option<String>,
+    rt_decay: Option<f32>,
+
+    // Keyswitches
+    sw_lokey: Option<u8>,
+    sw_hikey: Option<u8>,
+    sw_last: Option<u8>,
+    sw_down: Option<u8>,
+    sw_up: Option<u8>,
+    sw_previous: Option<u8>,
+    sw_default: Option<u8>,
+    sw_label: Option<String>,
+
+    // Amplitude envelope, keyed by opcode name without the `ampeg_` prefix
+    ampeg: HashMap<String, f32>,
+    // Filter envelope, keyed by opcode name without the `fileg_` prefix
+    fileg: HashMap<String, f32>,
+    // Pitch envelope, keyed by opcode name without the `pitcheg_` prefix
+    pitcheg: HashMap<String, f32>,
+    // SFZ v1 LFOs (`amplfo_*`, `pitchlfo_*`, `fillfo_*`), keyed by full opcode name
+    lfo: HashMap<String, f32>,
+    // SFZ v2 flex envelopes and LFOs (`egN_*`, `lfoN_*`), keyed by number then suffix
+    flex_eg: HashMap<u32, HashMap<String, f32>>,
+    flex_lfo: HashMap<u32, HashMap<String, f32>>,
+
+    // Velocity response
+    amp_veltrack: Option<f32>,
+    amp_velcurve: HashMap<u8, f32>,
 
     // CC conditions (for articulation switching)
     locc: HashMap<u8, u8>,
     hicc: HashMap<u8, u8>,
+
+    // Crossfades: `xfin_*`/`xfout_*` ranges keyed by full opcode name, and curves
+    xf: HashMap<String, u8>,
+    xf_velcurve: Option<String>,
+    xf_keycurve: Option<String>,
+    xf_cccurve: Option<String>,
 }
 
 impl OpcodeSet {
+    /// `end=-1` (any negative end) turns the region off
+    fn disabled(&self) -> bool {
+        self.end.is_some_and(|end| end < 0)
+    }
+
     fn merge(&mut self, other: &OpcodeSet) {
         macro_rules! merge_field {
             ($field:ident) => {
@@ -69,7 +153,10 @@ impl OpcodeSet {
         }
         merge_field!(sample);
         merge_field!(offset);
+        merge_field!(offset_random);
         merge_field!(end);
+        merge_field!(delay);
+        merge_field!(delay_random);
         merge_field!(lokey);
         merge_field!(hikey);
         merge_field!(pitch_keycenter);
@@ -77,14 +164,74 @@ impl OpcodeSet {
         merge_field!(lovel);
         merge_field!(hivel);
         merge_field!(loop_mode);
+        merge_field!(direction);
         merge_field!(loop_start);
         merge_field!(loop_end);
+        merge_field!(loop_type);
+        merge_field!(loop_count);
+        merge_field!(loop_crossfade);
+        merge_field!(pitch_keytrack);
+        merge_field!(transpose);
+        merge_field!(pitch_veltrack);
         merge_field!(tune);
         merge_field!(volume);
         merge_field!(pan);
+        merge_field!(bend_up);
+        merge_field!(bend_down);
+        merge_field!(bend_step);
+        merge_field!(fil_type);
+        merge_field!(cutoff);
+        merge_field!(resonance);
+        merge_field!(fil_keytrack);
+        merge_field!(fil_keycenter);
+        merge_field!(fil_veltrack);
+        merge_field!(xf_velcurve);
+        merge_field!(amp_veltrack);
+        merge_field!(xf_keycurve);
+        merge_field!(xf_cccurve);
         merge_field!(seq_length);
         merge_field!(seq_position);
+        merge_field!(lorand);
+        merge_field!(hirand);
         merge_field!(group);
+        merge_field!(off_by);
+        merge_field!(off_mode);
+        merge_field!(off_time);
+        merge_field!(trigger);
+        merge_field!(rt_decay);
+        merge_field!(sw_lokey);
+        merge_field!(sw_hikey);
+        merge_field!(sw_last);
+        merge_field!(sw_down);
+        merge_field!(sw_up);
+        merge_field!(sw_previous);
+        merge_field!(sw_default);
+        merge_field!(sw_label);
+
+        for (name, &val) in &other.ampeg {
+            self.ampeg.insert(name.clone(), val);
+        }
+        for (name, &val) in &other.fileg {
+            self.fileg.insert(name.clone(), val);
+        }
+        for (name, &val) in &other.pitcheg {
+            self.pitcheg.insert(name.clone(), val);
+        }
+        for (name, &val) in &other.lfo {
+            self.lfo.insert(name.clone(), val);
+        }
+        for (num, opcodes) in &other.flex_eg {
+            let eg = self.flex_eg.entry(*num).or_default();
+            for (name, &val) in opcodes {
+                eg.insert(name.clone(), val);
+            }
+        }
+        for (num, opcodes) in &other.flex_lfo {
+            let lfo = self.flex_lfo.entry(*num).or_default();
+            for (name, &val) in opcodes {
+                lfo.insert(name.clone(), val);
+            }
+        }
 
         // Merge CC conditions
         for (&cc, &val) in &other.locc {
@@ -93,6 +240,15 @@ impl OpcodeSet {
         for (&cc, &val) in &other.hicc {
             self.hicc.insert(cc, val);
         }
+        for (name, &val) in &other.xf {
+            self.xf.insert(name.clone(), val);
+        }
+        for (&cc, &frames) in &other.offset_cc {
+            self.offset_cc.insert(cc, frames);
+        }
+        for (&vel, &gain) in &other.amp_velcurve {
+            self.amp_velcurve.insert(vel, gain);
+        }
     }
 }
 
@@ -108,6 +264,11 @@ struct SfzParser {
     pending_region: Option<OpcodeSet>,
     include_depth: usize,
     failed_samples: Vec<String>,
+    sustain_cc: Option<u8>,
+    sustain_lo: Option<f32>,
+    /// `<control>` transposition of note opcodes, in semitones and octaves
+    note_offset: i32,
+    octave_offset: i32,
 }
 
 impl SfzParser {
@@ -124,6 +285,10 @@ impl SfzParser {
             pending_region: None,
             include_depth: 0,
             failed_samples: Vec::new(),
+            sustain_cc: None,
+            sustain_lo: None,
+            note_offset: 0,
+            octave_offset: 0,
         }
     }
 
@@ -270,18 +435,36 @@ impl SfzParser {
     fn apply_opcodes_to_section(&mut self, line: &str) {
         let opcodes = parse_opcodes(line);
 
+        // Pedal settings apply to the whole instrument, whichever section sets them
+        if let Some(cc) = opcodes.get("sustain_cc").and_then(|v| v.parse::<u8>().ok()) {
+            self.sustain_cc = Some(cc.min(127));
+        }
+        if let Some(lo) = opcodes
+            .get("sustain_lo")
+            .and_then(|v| v.parse::<f32>().ok())
+        {
+            self.sustain_lo = Some(lo);
+        }
+
+        let note_shift = self.note_offset + 12 * self.octave_offset;
         match self.current_section {
             Section::Control => {
                 if let Some(path) = opcodes.get("default_path") {
                     self.default_path = path.clone();
                 }
+                if let Some(offset) = opcodes.get("note_offset").and_then(|v| v.parse().ok()) {
+                    self.note_offset = offset;
+                }
+                if let Some(offset) = opcodes.get("octave_offset").and_then(|v| v.parse().ok()) {
+                    self.octave_offset = offset;
+                }
             }
-            Section::Global => apply_opcodes(&mut self.global_opcodes, &opcodes),
-            Section::Master => apply_opcodes(&mut self.master_opcodes, &opcodes),
-            Section::Group => apply_opcodes(&mut self.group_opcodes, &opcodes),
+            Section::Global => apply_opcodes(&mut self.global_opcodes, &opcodes, note_shift),
+            Section::Master => apply_opcodes(&mut self.master_opcodes, &opcodes, note_shift),
+            Section::Group => apply_opcodes(&mut self.group_opcodes, &opcodes, note_shift),
             Section::Region => {
                 if let Some(ref mut ops) = self.pending_region {
-                    apply_opcodes(ops, &opcodes);
+                    apply_opcodes(ops, &opcodes, note_shift);
                 }
             }
             Section::None => {}
@@ -292,6 +475,8 @@ impl SfzParser {
         if let Some(region_ops) = self.pending_region.take() {
             match build_region(&region_ops, &self.base_dir, &self.default_path) {
                 Some(region) => self.regions.push(region),
+                // A disabled region isn't a failed load
+                None if region_ops.disabled() => {}
                 None => {
                     if let Some(s) = &region_ops.sample {
                         self.failed_samples.push(s.clone());
@@ -335,7 +520,14 @@ pub fn load_sfz(sfz_path: &Path) -> Result<Instrument, String> {
         ));
     }
 
-    Ok(Instrument::new(name, parser.regions))
+    let mut inst = Instrument::new(name, parser.regions);
+    if let Some(cc) = parser.sustain_cc {
+        inst.sustain_cc = cc;
+    }
+    if let Some(lo) = parser.sustain_lo {
+        inst.sustain_lo = lo;
+    }
+    Ok(inst)
 }
 
 fn strip_comments(line: &str) -> &str {
@@ -390,7 +582,9 @@ fn find_next_opcode(s: &str) -> Option<usize> {
     None
 }
 
-fn apply_opcodes(ops: &mut OpcodeSet, parsed: &HashMap<String, String>) {
+/// Apply parsed opcodes; `note_shift` semitones are added to note numbers
+fn apply_opcodes(ops: &mut OpcodeSet, parsed: &HashMap<String, String>, note_shift: i32) {
+    let parse_key = |s: &str| parse_note(s).map(|n| (n as i32 + note_shift).clamp(0, 127) as u8);
     for (key, value) in parsed {
         // Handle loccN and hiccN opcodes
         if key.starts_with("locc") {
@@ -410,32 +604,149 @@ fn apply_opcodes(ops: &mut OpcodeSet, parsed: &HashMap<String, String>) {
             continue;
         }
 
+        // `offset_ccN` / `offset_onccN`
+        if let Some(("offset", cc)) = split_cc_suffix(key) {
+            if let Ok(frames) = value.parse::<usize>() {
+                ops.offset_cc.insert(cc, frames);
+            }
+            continue;
+        }
+
+        if let Some(vel) = key.strip_prefix("amp_velcurve_") {
+            if let (Ok(vel), Ok(gain)) = (vel.parse::<u8>(), value.parse::<f32>()) {
+                ops.amp_velcurve.insert(vel.min(127), gain.clamp(0.0, 1.0));
+            }
+            continue;
+        }
+
+        if key.starts_with("xfin_") || key.starts_with("xfout_") {
+            let val = if key.ends_with("key") {
+                parse_key(value)
+            } else {
+                value.parse::<u8>().ok()
+            };
+            if let Some(val) = val {
+                ops.xf.insert(key.clone(), val.min(127));
+            }
+            continue;
+        }
+
+        if let Some(name) = key.strip_prefix("ampeg_") {
+            if let Ok(val) = value.parse::<f32>() {
+                ops.ampeg.insert(name.to_string(), val);
+            }
+            continue;
+        }
+        if let Some(name) = key.strip_prefix("fileg_") {
+            if let Ok(val) = value.parse::<f32>() {
+                ops.fileg.insert(name.to_string(), val);
+            }
+            continue;
+        }
+        if let Some(name) = key.strip_prefix("pitcheg_") {
+            if let Ok(val) = value.parse::<f32>() {
+                ops.pitcheg.insert(name.to_string(), val);
+            }
+            continue;
+        }
+        if let Some((num, name)) = split_flex_opcode(key, "eg") {
+            if let Ok(val) = value.parse::<f32>() {
+                ops.flex_eg
+                    .entry(num)
+                    .or_default()
+                    .insert(name.to_string(), val);
+            }
+            continue;
+        }
+        if let Some((num, name)) = split_flex_opcode(key, "lfo") {
+            if let Ok(val) = value.parse::<f32>() {
+                ops.flex_lfo
+                    .entry(num)
+                    .or_default()
+                    .insert(name.to_string(), val);
+            }
+            continue;
+        }
+        if ["amplfo_", "pitchlfo_", "fillfo_"]
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+        {
+            if let Ok(val) = value.parse::<f32>() {
+                ops.lfo.insert(key.clone(), val);
+            }
+            continue;
+        }
+
         match key.as_str() {
             "sample" => ops.sample = Some(value.clone()),
             "offset" => ops.offset = value.parse().ok(),
+            "offset_random" => ops.offset_random = value.parse().ok(),
             "end" => ops.end = value.parse().ok(),
+            "delay" => ops.delay = value.parse().ok(),
+            "delay_random" => ops.delay_random = value.parse().ok(),
             "key" => {
-                if let Some(note) = parse_note(value) {
+                if let Some(note) = parse_key(value) {
                     ops.key = Some(note);
                     ops.lokey = Some(note);
                     ops.hikey = Some(note);
-                    ops.pitch_keycenter = Some(note);
+                    ops.pitch_keycenter = Some(KeyCenter::Note(note));
+                }
+            }
+            "lokey" => ops.lokey = parse_key(value),
+            "hikey" => ops.hikey = parse_key(value),
+            "pitch_keycenter" => {
+                if value.eq_ignore_ascii_case("sample") {
+                    ops.pitch_keycenter = Some(KeyCenter::FromSample);
+                } else if let Some(note) = parse_key(value) {
+                    ops.pitch_keycenter = Some(KeyCenter::Note(note));
                 }
             }
-            "lokey" => ops.lokey = parse_note(value),
-            "hikey" => ops.hikey = parse_note(value),
-            "pitch_keycenter" => ops.pitch_keycenter = parse_note(value),
             "lovel" => ops.lovel = value.parse().ok(),
             "hivel" => ops.hivel = value.parse().ok(),
             "loop_mode" => ops.loop_mode = Some(value.clone()),
+            "direction" => ops.direction = Some(value.to_lowercase()),
             "loop_start" => ops.loop_start = value.parse().ok(),
             "loop_end" => ops.loop_end = value.parse().ok(),
+            "loop_type" => ops.loop_type = Some(value.to_lowercase()),
+            "loop_count" => ops.loop_count = value.parse().ok(),
+            "loop_crossfade" => ops.loop_crossfade = value.parse().ok(),
+            "pitch_keytrack" => ops.pitch_keytrack = value.parse().ok(),
+            "transpose" => ops.transpose = value.parse().ok(),
+            "pitch_veltrack" => ops.pitch_veltrack = value.parse().ok(),
             "tune" => ops.tune = value.parse().ok(),
             "volume" => ops.volume = value.parse().ok(),
             "pan" => ops.pan = value.parse().ok(),
+            "bend_up" => ops.bend_up = value.parse().ok(),
+            "bend_down" => ops.bend_down = value.parse().ok(),
+            "bend_step" => ops.bend_step = value.parse().ok(),
+            "fil_type" => ops.fil_type = Some(value.to_lowercase()),
+            "cutoff" => ops.cutoff = value.parse().ok(),
+            "resonance" => ops.resonance = value.parse().ok(),
+            "fil_keytrack" => ops.fil_keytrack = value.parse().ok(),
+            "fil_keycenter" => ops.fil_keycenter = parse_key(value),
+            "fil_veltrack" => ops.fil_veltrack = value.parse().ok(),
+            "amp_veltrack" => ops.amp_veltrack = value.parse().ok(),
+            "xf_velcurve" => ops.xf_velcurve = Some(value.to_lowercase()),
+            "xf_keycurve" => ops.xf_keycurve = Some(value.to_lowercase()),
+            "xf_cccurve" => ops.xf_cccurve = Some(value.to_lowercase()),
             "seq_length" => ops.seq_length = value.parse().ok(),
             "seq_position" => ops.seq_position = value.parse().ok(),
+            "lorand" => ops.lorand = value.parse().ok(),
+            "hirand" => ops.hirand = value.parse().ok(),
             "group" => ops.group = value.parse().ok(),
+            "off_by" => ops.off_by = value.parse().ok(),
+            "off_mode" => ops.off_mode = Some(value.to_lowercase()),
+            "off_time" => ops.off_time = value.parse().ok(),
+            "trigger" => ops.trigger = Some(value.to_lowercase()),
+            "rt_decay" => ops.rt_decay = value.parse().ok(),
+            "sw_lokey" => ops.sw_lokey = parse_key(value),
+            "sw_hikey" => ops.sw_hikey = parse_key(value),
+            "sw_last" => ops.sw_last = parse_key(value),
+            "sw_down" => ops.sw_down = parse_key(value),
+            "sw_up" => ops.sw_up = parse_key(value),
+            "sw_previous" => ops.sw_previous = parse_key(value),
+            "sw_default" => ops.sw_default = parse_key(value),
+            "sw_label" => ops.sw_label = Some(value.clone()),
             _ => {} // Silently ignore not yet supported opcodes
         }
     }
@@ -484,7 +795,220 @@ fn parse_note(s: &str) -> Option<u8> {
     }
 }
 
+/// Opcodes (with their prefix stripped) that take the SFZ v1 `ccN` suffix, as in `attackcc1`
+const V1_CC_OPCODES: [&str; 7] = [
+    "delay", "attack", "hold", "decay", "sustain", "release", "depth",
+];
+
+/// Split a CC-modulated opcode name (`attack_oncc1`, `offset_cc1` or the v1 `attackcc1`)
+/// into (name, cc)
+fn split_cc_suffix(name: &str) -> Option<(&str, u8)> {
+    let (base, num) = if let Some(pos) = name.rfind("_oncc") {
+        (&name[..pos], &name[pos + 5..])
+    } else if let Some(pos) = name.rfind("_cc") {
+        (&name[..pos], &name[pos + 3..])
+    } else {
+        let pos = name.rfind("cc")?;
+        let base = &name[..pos];
+        if !V1_CC_OPCODES.contains(&base) {
+            return None;
+        }
+        (base, &name[pos + 2..])
+    };
+    if num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let cc = num.parse::<u8>().ok().filter(|&cc| cc < 128)?;
+    Some((base, cc))
+}
+
+fn eg_stage<'a>(stages: &'a mut EgStages, name: &str) -> Option<&'a mut f32> {
+    match name {
+        "delay" => Some(&mut stages.delay),
+        "attack" => Some(&mut stages.attack),
+        "hold" => Some(&mut stages.hold),
+        "decay" => Some(&mut stages.decay),
+        "sustain" => Some(&mut stages.sustain),
+        "release" => Some(&mut stages.release),
+        _ => None,
+    }
+}
+
+/// Build an envelope from `*eg_` opcodes (prefix already stripped).
+/// `sustain` is the level in percent when `*eg_sustain` is not given.
+fn build_envelope(opcodes: &HashMap<String, f32>, sustain: f32) -> EnvelopeDef {
+    let mut env = EnvelopeDef::default();
+    env.stages.sustain = sustain;
+    for (name, &value) in opcodes {
+        if name == "depth" {
+            env.depth = value;
+        } else if name == "vel2depth" {
+            env.vel2depth = value;
+        } else if let Some(stage) = eg_stage(&mut env.stages, name) {
+            *stage = value;
+        } else if let Some(stage) = name
+            .strip_prefix("vel2")
+            .and_then(|n| eg_stage(&mut env.vel2, n))
+        {
+            *stage = value;
+        } else if let Some((base, cc)) = split_cc_suffix(name)
+            && let Some(stage) = eg_stage(env.cc_mut(cc), base)
+        {
+            *stage = value;
+        }
+    }
+    env
+}
+
+/// Build an LFO from the v1 opcodes starting with `prefix` (e.g. `pitchlfo_`)
+fn build_lfo(opcodes: &HashMap<String, f32>, prefix: &str) -> LfoDef {
+    let mut lfo = LfoDef::default();
+    for (name, &value) in opcodes {
+        let Some(name) = name.strip_prefix(prefix) else {
+            continue;
+        };
+        match name {
+            "delay" => lfo.delay = value,
+            "fade" => lfo.fade = value,
+            "freq" => lfo.freq = value,
+            "depth" => lfo.depth = value,
+            _ => {
+                if let Some(("depth", cc)) = split_cc_suffix(name) {
+                    lfo.depth_cc.push((cc, value));
+                }
+            }
+        }
+    }
+    lfo
+}
+
+/// Split an SFZ v2 modulator opcode (`eg01_time1`) into its number and suffix
+fn split_flex_opcode<'a>(name: &'a str, prefix: &str) -> Option<(u32, &'a str)> {
+    let rest = name.strip_prefix(prefix)?;
+    let (num, suffix) = rest.split_once('_')?;
+    if num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((num.parse().ok()?, suffix))
+}
+
+/// Collect the modulation targets of an `egN`/`lfoN` opcode set
+fn flex_targets(opcodes: &HashMap<String, f32>) -> SmallVec<[(ModTarget, f32); 2]> {
+    opcodes
+        .iter()
+        .filter_map(|(name, &amount)| Some((ModTarget::from_sfz(name)?, amount)))
+        .collect()
+}
+
+/// Highest flex envelope point index read (`egN_timeK`/`egN_levelK`)
+const MAX_FLEX_EG_POINTS: usize = 64;
+
+/// Build the SFZ v2 flex envelopes, ordered by number
+fn build_flex_egs(egs: &HashMap<u32, HashMap<String, f32>>) -> SmallVec<[FlexEgDef; 2]> {
+    let mut nums: Vec<u32> = egs.keys().copied().collect();
+    nums.sort_unstable();
+    nums.iter()
+        .map(|num| {
+            let opcodes = &egs[num];
+            let mut eg = FlexEgDef {
+                sustain: opcodes.get("sustain").map(|&p| p as usize),
+                targets: flex_targets(opcodes),
+                ..FlexEgDef::default()
+            };
+            // Points run up to the highest one given; gaps start and end at 0
+            let last = opcodes
+                .keys()
+                .filter_map(|k| k.strip_prefix("time").or_else(|| k.strip_prefix("level")))
+                .filter_map(|n| n.parse::<usize>().ok())
+                .filter(|&n| n < MAX_FLEX_EG_POINTS)
+                .max();
+            for point in last.map_or(0..0, |last| 0..last + 1) {
+                let time = opcodes.get(&format!("time{point}")).copied();
+                let level = opcodes.get(&format!("level{point}")).copied();
+                eg.points
+                    .push((time.unwrap_or(0.0), level.unwrap_or(0.0).clamp(-1.0, 1.0)));
+            }
+            eg
+        })
+        .collect()
+}
+
+/// Build the SFZ v2 LFOs, ordered by number
+fn build_flex_lfos(lfos: &HashMap<u32, HashMap<String, f32>>) -> SmallVec<[FlexLfoDef; 2]> {
+    let mut nums: Vec<u32> = lfos.keys().copied().collect();
+    nums.sort_unstable();
+    nums.iter()
+        .map(|num| {
+            let opcodes = &lfos[num];
+            let get = |name: &str| opcodes.get(name).copied().unwrap_or(0.0);
+            FlexLfoDef {
+                freq: get("freq"),
+                delay: get("delay"),
+                fade: get("fade"),
+                phase: get("phase"),
+                wave: LfoWave::from_sfz(get("wave") as u32),
+                targets: flex_targets(opcodes),
+            }
+        })
+        .collect()
+}
+
+/// Build the crossfade ranges from `xfin_*`/`xfout_*` opcodes
+fn build_crossfade(ops: &OpcodeSet) -> CrossfadeDef {
+    let range = |dir: &str, kind: &str, lo_default: u8, hi_default: u8| {
+        let lo = ops.xf.get(&format!("{dir}_lo{kind}"));
+        let hi = ops.xf.get(&format!("{dir}_hi{kind}"));
+        (lo.is_some() || hi.is_some())
+            .then(|| (*lo.unwrap_or(&lo_default), *hi.unwrap_or(&hi_default)))
+    };
+    let curve = |name: &Option<String>| match name.as_deref() {
+        Some("gain") => XfCurve::Gain,
+        _ => XfCurve::Power,
+    };
+
+    let mut xf = CrossfadeDef {
+        vel_in: range("xfin", "vel", 0, 0),
+        vel_out: range("xfout", "vel", 127, 127),
+        key_in: range("xfin", "key", 0, 0),
+        key_out: range("xfout", "key", 127, 127),
+        vel_curve: curve(&ops.xf_velcurve),
+        key_curve: curve(&ops.xf_keycurve),
+        cc_curve: curve(&ops.xf_cccurve),
+        ..CrossfadeDef::default()
+    };
+
+    let mut ccs: Vec<(bool, u8)> = ops
+        .xf
+        .keys()
+        .filter_map(|name| {
+            let (fade_in, rest) = match name.strip_prefix("xfin_") {
+                Some(rest) => (true, rest),
+                None => (false, name.strip_prefix("xfout_")?),
+            };
+            let num = rest
+                .strip_prefix("locc")
+                .or_else(|| rest.strip_prefix("hicc"))?;
+            Some((fade_in, num.parse::<u8>().ok().filter(|&cc| cc < 128)?))
+        })
+        .collect();
+    ccs.sort_unstable();
+    ccs.dedup();
+    for (fade_in, cc) in ccs {
+        if fade_in {
+            let (lo, hi) = range("xfin", &format!("cc{cc}"), 0, 0).unwrap_or_default();
+            xf.cc_in.push((cc, lo, hi));
+        } else {
+            let (lo, hi) = range("xfout", &format!("cc{cc}"), 127, 127).unwrap_or_default();
+            xf.cc_out.push((cc, lo, hi));
+        }
+    }
+    xf
+}
+
 fn build_region(ops: &OpcodeSet, base_dir: &Path, default_path: &str) -> Option<Region> {
+    if ops.disabled() {
+        return None;
+    }
     let sample_name = ops.sample.as_ref()?;
     let sample_name_normalized = sample_name.replace('\\', "/");
 
@@ -495,41 +1019,290 @@ fn build_region(ops: &OpcodeSet, base_dir: &Path, default_path: &str) -> Option<
         base_dir.join(&dp).join(&sample_name_normalized)
     };
 
-    if !sample_path.exists() {
-        return None;
-    }
-
-    let audio = load_audio(&sample_path).ok()?;
+    // `*name` samples are built-in waveforms
+    let audio = match sample_name.strip_prefix('*') {
+        Some(wave) => generate_audio(wave)?,
+        None if sample_path.exists() => load_audio(&sample_path).ok()?,
+        None => return None,
+    };
+    let meta = audio.meta;
 
-    use crate::sample::LoopMode;
+    use crate::sample::{FilterDef, LoopMode, LoopType, OffMode, Trigger};
 
+    // Samples with a loop of their own loop continuously unless told otherwise
     let loop_mode = match ops.loop_mode.as_deref() {
         Some("loop_continuous") => LoopMode::Continuous,
         Some("loop_sustain") => LoopMode::Sustain,
+        Some("one_shot") => LoopMode::OneShot,
+        None if meta.loop_range.is_some() => LoopMode::Continuous,
         _ => LoopMode::NoLoop,
     };
+    let loop_type = match ops.loop_type.as_deref() {
+        Some("forward") => LoopType::Forward,
+        Some("backward") => LoopType::Backward,
+        Some("alternate") => LoopType::Alternate,
+        _ => meta.loop_type,
+    };
+    // SFZ `loop_end` is the last frame of the loop
+    let (loop_start, loop_end) = match (ops.loop_start, ops.loop_end.map(|end| end + 1)) {
+        (None, None) => meta.loop_range.unzip(),
+        points => points,
+    };
+    // `pitch_keycenter=sample`, or no key at all, uses the file's root note and fine tuning
+    let (root_note, file_tune) = match ops.pitch_keycenter.or(ops.key.map(KeyCenter::Note)) {
+        Some(KeyCenter::Note(note)) => (note, None),
+        Some(KeyCenter::FromSample) | None => (meta.root_note.unwrap_or(60), meta.tune_cents),
+    };
+
+    let off_mode = match ops.off_mode.as_deref() {
+        Some("normal") => OffMode::Normal,
+        Some("time") => OffMode::Time,
+        _ => OffMode::Fast,
+    };
+
+    let trigger = match ops.trigger.as_deref() {
+        Some("release") => Trigger::Release,
+        Some("first") => Trigger::First,
+        Some("legato") => Trigger::Legato,
+        Some("release_key") => Trigger::ReleaseKey,
+        _ => Trigger::Attack,
+    };
+
+    // A region without `cutoff` has no filter
+    let filter = ops.cutoff.map(|cutoff| FilterDef {
+        mode: match ops.fil_type.as_deref() {
+            Some(t) if t.starts_with("hpf") => FilterMode::HP,
+            Some(t) if t.starts_with("bpf") => FilterMode::BP,
+            _ => FilterMode::LP,
+        },
+        cutoff: cutoff.max(1.0),
+        resonance: ops.resonance.unwrap_or(0.0),
+        keytrack: ops.fil_keytrack.unwrap_or(0.0),
+        keycenter: ops.fil_keycenter.unwrap_or(60),
+        veltrack: ops.fil_veltrack.unwrap_or(0.0),
+    });
+
+    let mut amp_velcurve: SmallVec<[(u8, f32); 4]> =
+        ops.amp_velcurve.iter().map(|(&v, &g)| (v, g)).collect();
+    amp_velcurve.sort_unstable_by_key(|&(v, _)| v);
+
+    let seq_length = ops.seq_length.filter(|&n| n > 0);
+    let seq_position = ops.seq_position.unwrap_or(1).max(1);
+    if let Some(length) = seq_length
+        && seq_position > length
+    {
+        nih_plug::nih_log!(
+            "seq_position={} exceeds seq_length={}, region never plays: {}",
+            seq_position,
+            length,
+            sample_name
+        );
+    }
+
+    let mut cc_ranges: SmallVec<[(u8, u8, u8); 2]> = SmallVec::new();
+    for cc in ops.locc.keys().chain(ops.hicc.keys()) {
+        if cc_ranges.iter().any(|(n, _, _)| n == cc) {
+            continue;
+        }
+        let lo = ops.locc.get(cc).copied().unwrap_or(0);
+        let hi = ops.hicc.get(cc).copied().unwrap_or(127);
+        cc_ranges.push((*cc, lo, hi));
+    }
+
+    let keyswitch = KeyswitchDef {
+        range: match (ops.sw_lokey, ops.sw_hikey) {
+            (Some(lo), Some(hi)) => Some((lo, hi)),
+            (Some(key), None) | (None, Some(key)) => Some((key, key)),
+            (None, None) => None,
+        },
+        last: ops.sw_last,
+        down: ops.sw_down,
+        up: ops.sw_up,
+        previous: ops.sw_previous,
+        default: ops.sw_default,
+        label: ops.sw_label.clone(),
+    };
 
     let vol_db = ops.volume.unwrap_or(0.0);
-    Some(Region {
+    let mut region = Region {
         data: Arc::new(audio.samples),
         channels: audio.channels,
         sample_rate: audio.sample_rate as f32,
         num_frames: audio.num_frames,
-        root_note: ops.pitch_keycenter.or(ops.key).unwrap_or(60),
+        generator: audio.generator,
+        root_note,
         lo_note: ops.lokey.unwrap_or(0),
         hi_note: ops.hikey.unwrap_or(127),
         lo_vel: ops.lovel.unwrap_or(0),
         hi_vel: ops.hivel.unwrap_or(127),
-        loop_start: ops.loop_start,
-        loop_end: ops.loop_end,
+        offset: ops.offset.unwrap_or(0),
+        offset_random: ops.offset_random.unwrap_or(0),
+        offset_cc: ops
+            .offset_cc
+            .iter()
+            .map(|(&cc, &frames)| (cc, frames))
+            .collect(),
+        end: ops.end.map_or(audio.num_frames, |end| {
+            (end as usize + 1).min(audio.num_frames)
+        }),
+        reverse: ops.direction.as_deref() == Some("reverse"),
+        delay: ops.delay.unwrap_or(0.0).max(0.0),
+        delay_random: ops.delay_random.unwrap_or(0.0).max(0.0),
+        loop_start,
+        loop_end,
         loop_mode,
-        rr_group: ops.group.unwrap_or(0),
-        rr_seq: ops.seq_position.unwrap_or(1).saturating_sub(1),
-        tune_cents: ops.tune.unwrap_or(0.0),
+        loop_type,
+        loop_count: ops.loop_count,
+        loop_crossfade: ops.loop_crossfade.unwrap_or(0.0).max(0.0),
+        group: ops.group.unwrap_or(0),
+        off_by: ops.off_by,
+        off_mode,
+        // Without `off_time`, `off_mode=time` fades like `fast`
+        off_time: ops.off_time.unwrap_or(crate::FAST_OFF_MS / 1000.0),
+        // SFZ round robin is per region; without `seq_length` the region always plays
+        rr_group: 0,
+        rr_seq: seq_position - 1,
+        rr_length: seq_length,
+        lo_rand: ops.lorand.unwrap_or(0.0).clamp(0.0, 1.0),
+        hi_rand: ops.hirand.unwrap_or(1.0).clamp(0.0, 1.0),
+        trigger,
+        rt_decay: ops.rt_decay.unwrap_or(0.0),
+        amp_env: build_envelope(&ops.ampeg, 100.0),
+        amp_veltrack: ops.amp_veltrack.map(|t| (t / 100.0).clamp(-1.0, 1.0)),
+        amp_velcurve,
+        // SFZ modulation envelopes sustain at 0; without `fileg_*` the plugin params apply as-is
+        fil_env: build_envelope(&ops.fileg, if ops.fileg.is_empty() { 100.0 } else { 0.0 }),
+        pitch_env: build_envelope(&ops.pitcheg, 0.0),
+        amp_lfo: build_lfo(&ops.lfo, "amplfo_"),
+        pitch_lfo: build_lfo(&ops.lfo, "pitchlfo_"),
+        fil_lfo: build_lfo(&ops.lfo, "fillfo_"),
+        flex_egs: build_flex_egs(&ops.flex_eg),
+        flex_lfos: build_flex_lfos(&ops.flex_lfo),
+        filter,
+        crossfade: build_crossfade(ops),
+        cc_ranges,
+        keyswitch,
+        bend_up: ops.bend_up,
+        bend_down: ops.bend_down,
+        bend_step: ops.bend_step.unwrap_or(0.0),
+        pitch_keytrack: ops.pitch_keytrack.unwrap_or(100.0),
+        transpose: ops.transpose.unwrap_or(0),
+        pitch_veltrack: ops.pitch_veltrack.unwrap_or(0.0),
+        tune_cents: ops.tune.or(file_tune).unwrap_or(0.0),
         volume_db: vol_db,
         volume_lin: crate::dsp::db_to_linear(vol_db),
         pan: ops.pan.map(|p| p / 100.0).unwrap_or(0.0),
         #[cfg(debug_assertions)]
         sample_path: sample_path.to_string_lossy().to_string(),
-    })
+    };
+    if let Some(fix) = region.validate_loop() {
+        nih_plug::nih_log!("Loop points fixed ({}): {}", fix, sample_name);
+    }
+    Some(region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cc_suffix_forms() {
+        assert_eq!(split_cc_suffix("attack_oncc1"), Some(("attack", 1)));
+        assert_eq!(split_cc_suffix("offset_cc64"), Some(("offset", 64)));
+        assert_eq!(split_cc_suffix("attackcc7"), Some(("attack", 7)));
+        assert_eq!(split_cc_suffix("depthcc1"), Some(("depth", 1)));
+    }
+
+    #[test]
+    fn cc_suffix_rejects_other_opcodes() {
+        assert_eq!(split_cc_suffix("vendor_acc12"), None);
+        assert_eq!(split_cc_suffix("synthcc3"), None);
+        assert_eq!(split_cc_suffix("attack_oncc128"), None);
+        assert_eq!(split_cc_suffix("attack_oncc+1"), None);
+        assert_eq!(split_cc_suffix("attack_oncc"), None);
+    }
+
+    fn apply(ops: &mut OpcodeSet, opcodes: &[(&str, &str)]) {
+        let parsed = opcodes
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        apply_opcodes(ops, &parsed, 0);
+    }
+
+    #[test]
+    fn pitch_keycenter_sample_is_explicit() {
+        let mut group = OpcodeSet::default();
+        apply(&mut group, &[("pitch_keycenter", "62")]);
+        let mut region = OpcodeSet::default();
+        apply(&mut region, &[("pitch_keycenter", "sample")]);
+
+        let mut merged = group.clone();
+        merged.merge(&region);
+        assert_eq!(merged.pitch_keycenter, Some(KeyCenter::FromSample));
+
+        // An unreadable value leaves the inherited key alone
+        let mut bad = OpcodeSet::default();
+        apply(&mut bad, &[("pitch_keycenter", "nope")]);
+        let mut merged = group.clone();
+        merged.merge(&bad);
+        assert_eq!(merged.pitch_keycenter, Some(KeyCenter::Note(62)));
+    }
+
+    fn flex_eg(opcodes: &[(&str, f32)]) -> FlexEgDef {
+        let opcodes = opcodes.iter().map(|&(k, v)| (k.to_string(), v)).collect();
+        build_flex_egs(&HashMap::from([(1, opcodes)])).remove(0)
+    }
+
+    #[test]
+    fn flex_eg_keeps_zero_points() {
+        let eg = flex_eg(&[
+            ("sustain", 0.0),
+            ("level0", 0.0),
+            ("time1", 0.5),
+            ("level1", 0.0),
+        ]);
+        assert_eq!(eg.sustain, Some(0));
+        assert_eq!(eg.points.as_slice(), &[(0.0, 0.0), (0.5, 0.0)]);
+    }
+
+    #[test]
+    fn flex_eg_fills_unspecified_points() {
+        let eg = flex_eg(&[("time2", 1.0), ("level2", 1.0)]);
+        assert_eq!(eg.sustain, None);
+        assert_eq!(eg.points.as_slice(), &[(0.0, 0.0), (0.0, 0.0), (1.0, 1.0)]);
+    }
+
+    #[test]
+    fn loop_end_is_the_last_loop_frame() {
+        let mut ops = OpcodeSet::default();
+        apply(
+            &mut ops,
+            &[
+                ("sample", "*sine"),
+                ("loop_mode", "loop_continuous"),
+                ("loop_start", "100"),
+                ("loop_end", "199"),
+            ],
+        );
+        let region = build_region(&ops, Path::new("."), "").unwrap();
+        assert_eq!((region.loop_start, region.loop_end), (Some(100), Some(200)));
+
+        // A loop end past the sample is clamped to its last frame
+        apply(&mut ops, &[("loop_end", "99999")]);
+        let region = build_region(&ops, Path::new("."), "").unwrap();
+        assert_eq!(region.loop_end, Some(region.end));
+    }
+
+    #[test]
+    fn negative_end_disables_the_region() {
+        let mut ops = OpcodeSet::default();
+        apply(&mut ops, &[("sample", "*sine"), ("end", "-1")]);
+        assert_eq!(ops.end, Some(-1));
+        assert!(build_region(&ops, Path::new("."), "").is_none());
+
+        apply(&mut ops, &[("end", "99")]);
+        let region = build_region(&ops, Path::new("."), "").unwrap();
+        assert_eq!(region.end, 100);
+    }
 }
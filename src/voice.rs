@@ -1,5 +1,49 @@
-use crate::dsp::{Adsr, FilterMode, ZdfSvf, flush_denormals};
-use crate::sample::{Instrument, LoopMode};
+use crate::dsp::{Adsr, FilterMode, FlexEnv, Lfo, Rng, ZdfSvf, db_to_linear, flush_denormals};
+use crate::sample::{
+    EgStages, FlexLfoDef, Generator, Instrument, LfoDef, LoopMode, LoopType, ModTarget,
+};
+
+/// Cutoff range of the brightness and pressure expressions, in cents
+const EXPR_CUTOFF_RANGE: f32 = 4800.0;
+/// The expression filter is bypassed at or above this cutoff
+const EXPR_FILTER_OPEN_HZ: f32 = 20000.0;
+const EXPR_FILTER_Q: f32 = 0.707;
+
+/// Peak level of the built-in `*noise` sample
+const NOISE_LEVEL: f32 = 0.5;
+const NOISE_SEED: u32 = 0x5eed;
+
+// Global LFO range at full depth
+const GLOBAL_LFO_PITCH_CENTS: f32 = 100.0;
+const GLOBAL_LFO_FILTER_CENTS: f32 = 2400.0;
+const GLOBAL_LFO_AMP_DB: f32 = 6.0;
+
+/// SFZ v2 modulators per voice; further `egN`/`lfoN` of a region are ignored
+const MAX_FLEX_EGS: usize = 4;
+const MAX_FLEX_LFOS: usize = 4;
+
+/// Destination of the plugin's global LFO
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LfoTarget {
+    Pitch,
+    Filter,
+    Amp,
+}
+
+/// Per-voice note expression (CLAP polyphonic expressions, MPE)
+#[derive(Clone, Copy)]
+pub enum Expression {
+    /// Pitch offset in semitones
+    Tuning(f32),
+    /// Linear gain (1 = unity)
+    Volume(f32),
+    /// -1..1, 0 = center
+    Pan(f32),
+    /// 0..1, 0.5 = neutral; moves the filter cutoff up or down
+    Brightness(f32),
+    /// 0..1; opens the filter cutoff
+    Pressure(f32),
+}
 
 pub struct Voice {
     pub active: bool,
@@ -10,13 +54,83 @@ pub struct Voice {
 
     pub region_idx: usize,
     pub position: f64,
+    /// Playing toward the start of the sample (reversed, or in a ping-pong loop)
+    backward: bool,
+    /// Loop repeats so far, for `loop_count`
+    loops_done: u32,
+    /// Samples left before playback starts (`delay`)
+    delay_samples: u32,
+    /// Effective rate including pitch modulation
     pub playback_rate: f64,
+    /// Rate at note-on, before pitch modulation
+    base_rate: f64,
+    /// Source of the built-in `*noise` sample
+    noise: Rng,
+
+    /// Pitch bend in cents, gliding toward `bend_target`
+    bend_cents: f32,
+    bend_target: f32,
+    glide_coef: f32,
+
+    // Note expressions
+    expr_tuning: f32,
+    expr_gain: f32,
+    expr_pan: f32,
+    expr_brightness: f32,
+    expr_pressure: f32,
+    /// Cutoff multiplier from brightness and pressure
+    cutoff_ratio: f32,
+
+    /// Key/velocity crossfade gain, fixed at note-on
+    xf_note_gain: f32,
+    /// CC crossfade gain, gliding toward `xf_cc_target`
+    xf_cc_gain: f32,
+    xf_cc_target: f32,
 
     pub env: Adsr,
+    /// Region envelope resolved at note-on; the plugin ADSR params are applied on top
+    amp_eg: EgStages,
+    /// Release time set by a choke, kept when the envelope params change
+    choke_ms: Option<f32>,
+    fil_env: ModEnv,
+    pitch_env: ModEnv,
+    /// Current pitch modulation (envelope and LFOs) in cents
+    pitch_mod_cents: f32,
+
+    // Region LFOs
+    amp_lfo: Lfo,
+    pitch_lfo: Lfo,
+    fil_lfo: Lfo,
+    /// Region LFO depths: dB for amplitude, cents for pitch and filter
+    amp_lfo_depth: f32,
+    pitch_lfo_depth: f32,
+    fil_lfo_depth: f32,
+    /// The plugin's global LFO output times its depth (-1..1), scaled to the target's range
+    global_lfo: f32,
+    global_lfo_target: LfoTarget,
+
+    // SFZ v2 modulators, driven by the region's `flex_egs`/`flex_lfos`
+    flex_egs: [FlexEnv; MAX_FLEX_EGS],
+    flex_lfos: [Lfo; MAX_FLEX_LFOS],
     pub filter_l: ZdfSvf,
     pub filter_r: ZdfSvf,
 
+    // Region filter, ahead of the global filter
+    region_filter_l: ZdfSvf,
+    region_filter_r: ZdfSvf,
+    region_mode: FilterMode,
+    region_cutoff: f32,
+    region_q: f32,
+    last_region_cutoff: f32,
+
+    // Low-pass driven by brightness and pressure when no other filter is active
+    expr_filter_l: ZdfSvf,
+    expr_filter_r: ZdfSvf,
+    last_expr_cutoff: f32,
+
     pub releasing: bool,
+    /// Started by a release trigger; ignores later note-offs
+    pub release_triggered: bool,
     pub age: u64,
 
     last_cutoff: f32,
@@ -40,13 +154,62 @@ impl Voice {
 
             region_idx: 0,
             position: 0.0,
+            backward: false,
+            loops_done: 0,
+            delay_samples: 0,
             playback_rate: 1.0,
+            base_rate: 1.0,
+            noise: Rng::new(NOISE_SEED),
+
+            bend_cents: 0.0,
+            bend_target: 0.0,
+            glide_coef: glide_coef(sr),
+
+            expr_tuning: 0.0,
+            expr_gain: 1.0,
+            expr_pan: 0.0,
+            expr_brightness: 0.5,
+            expr_pressure: 0.0,
+            cutoff_ratio: 1.0,
+
+            xf_note_gain: 1.0,
+            xf_cc_gain: 1.0,
+            xf_cc_target: 1.0,
 
             env: Adsr::new(sr),
+            amp_eg: EgStages::default(),
+            choke_ms: None,
+            fil_env: ModEnv::new(sr),
+            pitch_env: ModEnv::new(sr),
+            pitch_mod_cents: 0.0,
+
+            amp_lfo: Lfo::new(sr),
+            pitch_lfo: Lfo::new(sr),
+            fil_lfo: Lfo::new(sr),
+            amp_lfo_depth: 0.0,
+            pitch_lfo_depth: 0.0,
+            fil_lfo_depth: 0.0,
+            global_lfo: 0.0,
+            global_lfo_target: LfoTarget::Pitch,
+
+            flex_egs: std::array::from_fn(|_| FlexEnv::new(sr)),
+            flex_lfos: std::array::from_fn(|_| Lfo::new(sr)),
             filter_l: ZdfSvf::new(sr),
             filter_r: ZdfSvf::new(sr),
 
+            region_filter_l: ZdfSvf::new(sr),
+            region_filter_r: ZdfSvf::new(sr),
+            region_mode: FilterMode::Off,
+            region_cutoff: 0.0,
+            region_q: 0.0,
+            last_region_cutoff: -1.0,
+
+            expr_filter_l: ZdfSvf::new(sr),
+            expr_filter_r: ZdfSvf::new(sr),
+            last_expr_cutoff: -1.0,
+
             releasing: false,
+            release_triggered: false,
             age: 0,
 
             last_cutoff: -1.0,
@@ -61,11 +224,30 @@ impl Voice {
     }
 
     pub fn set_sample_rate(&mut self, sr: f32) {
+        self.glide_coef = glide_coef(sr);
         self.env.set_sample_rate(sr);
+        self.fil_env.env.set_sample_rate(sr);
+        self.pitch_env.env.set_sample_rate(sr);
+        self.amp_lfo.set_sample_rate(sr);
+        self.pitch_lfo.set_sample_rate(sr);
+        self.fil_lfo.set_sample_rate(sr);
+        for eg in &mut self.flex_egs {
+            eg.set_sample_rate(sr);
+        }
+        for lfo in &mut self.flex_lfos {
+            lfo.set_sample_rate(sr);
+        }
         self.filter_l.set_sample_rate(sr);
         self.filter_r.set_sample_rate(sr);
+        self.region_filter_l.set_sample_rate(sr);
+        self.region_filter_r.set_sample_rate(sr);
+        self.last_region_cutoff = -1.0;
+        self.expr_filter_l.set_sample_rate(sr);
+        self.expr_filter_r.set_sample_rate(sr);
+        self.last_expr_cutoff = -1.0;
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         &mut self,
         channel: u8,
@@ -73,6 +255,7 @@ impl Voice {
         velocity: f32,
         region_idx: usize,
         playback_rate: f64,
+        amp_eg: EgStages,
         age: u64,
     ) {
         self.active = true;
@@ -83,21 +266,191 @@ impl Voice {
 
         self.region_idx = region_idx;
         self.position = 0.0;
+        self.backward = false;
+        self.loops_done = 0;
+        self.delay_samples = 0;
         self.playback_rate = playback_rate;
+        self.base_rate = playback_rate;
+        self.bend_cents = 0.0;
+        self.bend_target = 0.0;
+
+        self.expr_tuning = 0.0;
+        self.expr_gain = 1.0;
+        self.expr_pan = 0.0;
+        self.expr_brightness = 0.5;
+        self.expr_pressure = 0.0;
+        self.cutoff_ratio = 1.0;
+        self.last_expr_cutoff = -1.0;
+        self.expr_filter_l.reset();
+        self.expr_filter_r.reset();
+        self.xf_note_gain = 1.0;
+        self.xf_cc_gain = 1.0;
+        self.xf_cc_target = 1.0;
 
         self.releasing = false;
+        self.release_triggered = false;
         self.age = age;
 
+        self.amp_eg = amp_eg;
+        self.choke_ms = None;
+        self.update_env();
         self.env.reset();
         self.env.note_on();
         self.filter_l.reset();
         self.filter_r.reset();
+        self.set_region_filter(FilterMode::Off, 0.0, 0.0);
+        self.fil_env.start(EgStages::default(), 0.0);
+        self.pitch_env.start(EgStages::default(), 0.0);
+        self.pitch_mod_cents = 0.0;
+    }
+
+    /// Set the crossfade gains at note-on: key/velocity and CC
+    pub fn set_crossfade(&mut self, note_gain: f32, cc_gain: f32) {
+        self.xf_note_gain = note_gain;
+        self.xf_cc_gain = cc_gain;
+        self.xf_cc_target = cc_gain;
+    }
+
+    /// Follow a CC crossfade change, smoothed over a few ms
+    pub fn set_cc_crossfade(&mut self, cc_gain: f32) {
+        self.xf_cc_target = cc_gain;
+    }
+
+    /// Restart the region LFOs; depths are set separately as they follow CCs
+    pub fn start_lfos(&mut self, amp: &LfoDef, pitch: &LfoDef, fil: &LfoDef) {
+        self.amp_lfo.start(amp.delay, amp.fade, amp.freq);
+        self.pitch_lfo.start(pitch.delay, pitch.fade, pitch.freq);
+        self.fil_lfo.start(fil.delay, fil.fade, fil.freq);
+    }
+
+    /// Restart the SFZ v2 modulators for the region's LFO definitions
+    pub fn start_flex(&mut self, lfos: &[FlexLfoDef]) {
+        for eg in &mut self.flex_egs {
+            eg.start();
+        }
+        for (lfo, def) in self.flex_lfos.iter_mut().zip(lfos) {
+            lfo.start(def.delay, def.fade, def.freq);
+            lfo.set_wave(def.wave, def.phase);
+        }
+    }
+
+    /// Region LFO depths: amplitude in dB, pitch and filter in cents
+    pub fn set_lfo_depths(&mut self, amp_db: f32, pitch_cents: f32, fil_cents: f32) {
+        self.amp_lfo_depth = amp_db;
+        self.pitch_lfo_depth = pitch_cents;
+        self.fil_lfo_depth = fil_cents;
+    }
+
+    /// Call from process() with the global LFO's current value, already scaled by depth
+    #[inline]
+    pub fn set_global_lfo(&mut self, value: f32, target: LfoTarget) {
+        self.global_lfo = value;
+        self.global_lfo_target = target;
+    }
+
+    /// Set the region's pitch envelope, resolved at note-on, with its depth in cents
+    pub fn set_pitch_env(&mut self, eg: EgStages, depth: f32) {
+        self.pitch_env.start(eg, depth);
+    }
+
+    /// Set the region's filter envelope, resolved at note-on, with its depth in cents
+    pub fn set_filter_env(&mut self, eg: EgStages, depth: f32) {
+        self.fil_env.start(eg, depth);
+    }
+
+    /// Configure the region filter for this note (`FilterMode::Off` bypasses it)
+    pub fn set_region_filter(&mut self, mode: FilterMode, cutoff_hz: f32, q: f32) {
+        self.region_mode = mode;
+        self.region_cutoff = cutoff_hz;
+        self.region_q = q;
+        self.last_region_cutoff = -1.0;
+        self.region_filter_l.reset();
+        self.region_filter_r.reset();
+    }
+
+    /// Set the start frame, direction and delay before playback, after `start`
+    pub fn set_start(&mut self, frame: usize, reverse: bool, delay_samples: u32) {
+        self.position = frame as f64;
+        self.backward = reverse;
+        self.delay_samples = delay_samples;
+    }
+
+    /// Seed the `*noise` generator; each voice gets its own so layers aren't identical
+    pub fn set_noise_seed(&mut self, seed: u32) {
+        self.noise.reseed(seed);
+    }
+
+    /// Set the pitch bend in cents; with `glide` the change is smoothed over a few ms
+    pub fn set_bend(&mut self, cents: f32, glide: bool) {
+        self.bend_target = cents;
+        if !glide {
+            self.bend_cents = cents;
+            self.update_rate();
+        }
+    }
+
+    pub fn set_expression(&mut self, expr: Expression) {
+        match expr {
+            Expression::Tuning(semitones) => {
+                self.expr_tuning = semitones;
+                self.update_rate();
+            }
+            Expression::Volume(gain) => self.expr_gain = gain.max(0.0),
+            Expression::Pan(pan) => self.expr_pan = pan.clamp(-1.0, 1.0),
+            Expression::Brightness(brightness) => {
+                self.expr_brightness = brightness.clamp(0.0, 1.0);
+                self.update_cutoff_ratio();
+            }
+            Expression::Pressure(pressure) => {
+                self.expr_pressure = pressure.clamp(0.0, 1.0);
+                self.update_cutoff_ratio();
+            }
+        }
+    }
+
+    #[inline]
+    fn update_rate(&mut self) {
+        let cents = self.bend_cents + self.expr_tuning * 100.0 + self.pitch_mod_cents;
+        self.playback_rate = self.base_rate * 2.0f64.powf(cents as f64 / 1200.0);
+    }
+
+    fn update_cutoff_ratio(&mut self) {
+        let cents = ((self.expr_brightness - 0.5) * 2.0 + self.expr_pressure) * EXPR_CUTOFF_RANGE;
+        self.cutoff_ratio = 2.0f32.powf(cents / 1200.0);
     }
 
     pub fn release(&mut self) {
         if self.active && !self.releasing {
             self.releasing = true;
             self.env.note_off();
+            self.release_modulators();
+        }
+    }
+
+    /// Move the filter, pitch and flex envelopes into their release stages
+    fn release_modulators(&mut self) {
+        self.fil_env.env.note_off();
+        self.pitch_env.env.note_off();
+        for eg in &mut self.flex_egs {
+            eg.release();
+        }
+    }
+
+    /// Turn the voice off for an exclusive group: fade over `fade_ms`, or use the
+    /// normal release when `None`
+    pub fn choke(&mut self, fade_ms: Option<f32>) {
+        if !self.active {
+            return;
+        }
+        match fade_ms {
+            Some(ms) => {
+                self.releasing = true;
+                self.choke_ms = Some(ms);
+                self.update_env();
+                self.env.note_off();
+                self.release_modulators();
+            }
+            None => self.release(),
         }
     }
 
@@ -113,11 +466,17 @@ impl Voice {
         filter_cutoff: f32,
         filter_q: f32,
         filter_mode: FilterMode,
+        expr_cutoff: f32,
     ) -> (f32, f32) {
         if !self.active {
             return (0.0, 0.0);
         }
 
+        if self.delay_samples > 0 {
+            self.delay_samples -= 1;
+            return (0.0, 0.0);
+        }
+
         let env = self.env.next();
         if self.env.is_idle() {
             self.active = false;
@@ -132,57 +491,163 @@ impl Voice {
             }
         };
 
-        let end_frame = region.num_frames;
-        if self.position >= end_frame as f64 {
-            match region.loop_mode {
-                LoopMode::Continuous => {
-                    if let (Some(start), Some(end)) = (region.loop_start, region.loop_end) {
-                        let loop_len = (end - start) as f64;
-                        if loop_len > 0.0 {
-                            self.position =
-                                start as f64 + ((self.position - start as f64) % loop_len);
-                        }
+        // Sustain loops wrap until release, then play through; so does any loop after
+        // `loop_count` repeats
+        let looping = match region.loop_mode {
+            LoopMode::Continuous => true,
+            LoopMode::Sustain => !self.releasing,
+            LoopMode::NoLoop | LoopMode::OneShot => false,
+        } && region.loop_count.is_none_or(|n| self.loops_done < n);
+        let loop_range = match (region.loop_start, region.loop_end) {
+            (Some(start), Some(end)) if looping && end > start => Some((start as f64, end as f64)),
+            _ => None,
+        };
+
+        match loop_range {
+            Some((start, end)) => {
+                let len = end - start;
+                if !self.backward && self.position >= end {
+                    if region.loop_type == LoopType::Forward {
+                        self.position = start + (self.position - end) % len;
+                        self.loops_done += 1;
+                    } else {
+                        self.position = (2.0 * end - self.position).max(start);
+                        self.backward = true;
                     }
-                }
-                LoopMode::Sustain if !self.releasing => {
-                    if let (Some(start), Some(end)) = (region.loop_start, region.loop_end) {
-                        let loop_len = (end - start) as f64;
-                        if loop_len > 0.0 {
-                            self.position =
-                                start as f64 + ((self.position - start as f64) % loop_len);
-                        }
+                } else if self.backward && self.position < start {
+                    if region.loop_type == LoopType::Alternate {
+                        self.position = (2.0 * start - self.position).min(end);
+                        self.backward = false;
+                    } else {
+                        self.position = end - (start - self.position) % len;
                     }
-                }
-                _ => {
-                    self.active = false;
-                    return (0.0, 0.0);
+                    self.loops_done += 1;
                 }
             }
+            // Out of the loop, play on in the region's own direction
+            None => self.backward = region.reverse,
         }
 
-        // Sustain loop: wrap during sustain, play through end on release
-        if region.loop_mode == LoopMode::Sustain && !self.releasing {
-            if let (Some(start), Some(end)) = (region.loop_start, region.loop_end) {
-                if self.position >= end as f64 {
-                    let loop_len = (end - start) as f64;
-                    if loop_len > 0.0 {
-                        self.position = start as f64 + ((self.position - end as f64) % loop_len);
-                    }
-                }
+        if self.backward {
+            if self.position < region.offset as f64 {
+                self.active = false;
+                return (0.0, 0.0);
             }
+        } else if self.position >= region.end as f64 {
+            self.active = false;
+            return (0.0, 0.0);
         }
 
-        let (mut l, mut r) = region.get_sample_stereo(self.position);
+        let (mut l, mut r) = match (region.generator.as_deref(), loop_range) {
+            // Noise is made at the output rate, so it doesn't follow pitch
+            (Some(Generator::Noise), _) => {
+                region.mono_to_stereo((self.noise.next_f32() * 2.0 - 1.0) * NOISE_LEVEL)
+            }
+            (Some(Generator::Wavetable(wt)), _) => {
+                region.mono_to_stereo(wt.sample(self.position, self.playback_rate))
+            }
+            (None, Some((start, end))) => {
+                region.get_sample_looped(self.position, start, end, self.backward)
+            }
+            (None, None) => region.get_sample_stereo(self.position),
+        };
 
         let region_gain = region.volume_lin;
         l *= region_gain;
         r *= region_gain;
 
-        let amp = env * self.velocity;
+        let global_lfo = self.global_lfo;
+        let (global_cents, global_fil_cents, global_db) = match self.global_lfo_target {
+            LfoTarget::Pitch => (global_lfo * GLOBAL_LFO_PITCH_CENTS, 0.0, 0.0),
+            LfoTarget::Filter => (0.0, global_lfo * GLOBAL_LFO_FILTER_CENTS, 0.0),
+            LfoTarget::Amp => (0.0, 0.0, global_lfo * GLOBAL_LFO_AMP_DB),
+        };
+
+        // SFZ v2 modulators: envelopes are unipolar, LFOs bipolar
+        let mut flex = FlexMod::default();
+        for (eg, def) in self.flex_egs.iter_mut().zip(&region.flex_egs) {
+            let level = eg.next(&def.points, def.sustain);
+            for &(target, amount) in &def.targets {
+                flex.add(target, level, amount, true);
+            }
+        }
+        for (lfo, def) in self.flex_lfos.iter_mut().zip(&region.flex_lfos) {
+            let value = lfo.next();
+            for &(target, amount) in &def.targets {
+                flex.add(target, value, amount, false);
+            }
+        }
+
+        let lfo_db = self.amp_lfo.next() * self.amp_lfo_depth + global_db + flex.db;
+        let lfo_gain = if lfo_db != 0.0 {
+            db_to_linear(lfo_db)
+        } else {
+            1.0
+        };
+
+        if self.xf_cc_gain != self.xf_cc_target {
+            let diff = self.xf_cc_target - self.xf_cc_gain;
+            if diff.abs() < 1e-4 {
+                self.xf_cc_gain = self.xf_cc_target;
+            } else {
+                self.xf_cc_gain += diff * self.glide_coef;
+            }
+        }
+        let xf_gain = self.xf_note_gain * self.xf_cc_gain;
+
+        let amp = env * self.velocity * self.expr_gain * lfo_gain * flex.gain * xf_gain;
         l *= amp;
         r *= amp;
 
+        let pan = self.expr_pan + flex.pan;
+        if pan != 0.0 {
+            let (gl, gr) = crate::dsp::balance_gains(pan);
+            l *= gl;
+            r *= gr;
+        }
+
+        let fil_cents = self.fil_env.next()
+            + self.fil_lfo.next() * self.fil_lfo_depth
+            + global_fil_cents
+            + flex.cutoff_cents;
+        let cutoff_ratio = if fil_cents != 0.0 {
+            self.cutoff_ratio * 2.0f32.powf(fil_cents / 1200.0)
+        } else {
+            self.cutoff_ratio
+        };
+
+        if self.region_mode != FilterMode::Off {
+            let region_cutoff = self.region_cutoff * cutoff_ratio;
+            if region_cutoff != self.last_region_cutoff {
+                self.region_filter_l
+                    .set(region_cutoff, self.region_q, self.region_mode);
+                self.region_filter_r
+                    .set(region_cutoff, self.region_q, self.region_mode);
+                self.last_region_cutoff = region_cutoff;
+            }
+            l = self.region_filter_l.process(l);
+            r = self.region_filter_r.process(r);
+        }
+
+        // Without a region or global filter, brightness and pressure still have a
+        // low-pass to move, starting from the expression cutoff param
+        if self.region_mode == FilterMode::Off && filter_mode == FilterMode::Off {
+            let expr_cutoff = expr_cutoff * self.cutoff_ratio;
+            if expr_cutoff < EXPR_FILTER_OPEN_HZ {
+                if expr_cutoff != self.last_expr_cutoff {
+                    self.expr_filter_l
+                        .set(expr_cutoff, EXPR_FILTER_Q, FilterMode::LP);
+                    self.expr_filter_r
+                        .set(expr_cutoff, EXPR_FILTER_Q, FilterMode::LP);
+                    self.last_expr_cutoff = expr_cutoff;
+                }
+                l = self.expr_filter_l.process(l);
+                r = self.expr_filter_r.process(r);
+            }
+        }
+
         // Cache filter params: only recompute when they change
+        let filter_cutoff = filter_cutoff * cutoff_ratio;
         if filter_cutoff != self.last_cutoff
             || filter_q != self.last_q
             || filter_mode != self.last_filter_mode
@@ -196,7 +661,31 @@ impl Voice {
         l = self.filter_l.process(l);
         r = self.filter_r.process(r);
 
-        self.position += self.playback_rate;
+        let pitch_mod_cents = self.pitch_env.next()
+            + self.pitch_lfo.next() * self.pitch_lfo_depth
+            + global_cents
+            + flex.cents;
+        let mut rate_changed = pitch_mod_cents != self.pitch_mod_cents;
+        self.pitch_mod_cents = pitch_mod_cents;
+
+        if self.bend_cents != self.bend_target {
+            let diff = self.bend_target - self.bend_cents;
+            if diff.abs() < 0.01 {
+                self.bend_cents = self.bend_target;
+            } else {
+                self.bend_cents += diff * self.glide_coef;
+            }
+            rate_changed = true;
+        }
+        if rate_changed {
+            self.update_rate();
+        }
+
+        if self.backward {
+            self.position -= self.playback_rate;
+        } else {
+            self.position += self.playback_rate;
+        }
 
         (flush_denormals(l), flush_denormals(r))
     }
@@ -210,10 +699,195 @@ impl Voice {
         {
             return;
         }
-        self.env.set_ms(a_ms, d_ms, s, r_ms);
         self.last_a_ms = a_ms;
         self.last_d_ms = d_ms;
         self.last_s = s;
         self.last_r_ms = r_ms;
+        self.update_env();
+    }
+
+    /// Call from process() with the plugin's filter envelope params
+    #[inline]
+    pub fn set_filter_env_ms(&mut self, a_ms: f32, d_ms: f32, s: f32, r_ms: f32, depth: f32) {
+        self.fil_env.set_params([a_ms, d_ms, s, r_ms, depth]);
+    }
+
+    /// Combine the region envelope with the plugin params: times are added as
+    /// offsets and the sustain param scales the region's sustain level
+    fn update_env(&mut self) {
+        let eg = &self.amp_eg;
+        self.env.set_ms(
+            eg.attack * 1000.0 + self.last_a_ms.max(0.0),
+            eg.decay * 1000.0 + self.last_d_ms.max(0.0),
+            eg.sustain / 100.0 * self.last_s.max(0.0),
+            self.choke_ms
+                .unwrap_or(eg.release * 1000.0 + self.last_r_ms.max(0.0)),
+        );
+        self.env
+            .set_delay_hold_ms(eg.delay * 1000.0, eg.hold * 1000.0);
+    }
+}
+
+/// One-pole coefficient for a ~5 ms pitch glide
+fn glide_coef(sr: f32) -> f32 {
+    1.0 - (-1.0 / (0.005 * sr.max(1.0))).exp()
+}
+
+/// Summed output of the SFZ v2 modulators for one sample
+struct FlexMod {
+    gain: f32,
+    db: f32,
+    cents: f32,
+    cutoff_cents: f32,
+    /// -1..1
+    pan: f32,
+}
+
+impl Default for FlexMod {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            db: 0.0,
+            cents: 0.0,
+            cutoff_cents: 0.0,
+            pan: 0.0,
+        }
+    }
+}
+
+impl FlexMod {
+    /// Add a modulator `value` scaled by `amount`. A unipolar amplitude modulator at
+    /// 100% follows its level; a bipolar one swings the gain around unity.
+    #[inline]
+    fn add(&mut self, target: ModTarget, value: f32, amount: f32, unipolar: bool) {
+        match target {
+            ModTarget::Amplitude => {
+                let depth = amount / 100.0;
+                self.gain *= if unipolar {
+                    1.0 - depth * (1.0 - value)
+                } else {
+                    1.0 + depth * value
+                }
+                .max(0.0);
+            }
+            ModTarget::Volume => self.db += value * amount,
+            ModTarget::Pitch => self.cents += value * amount,
+            ModTarget::Cutoff => self.cutoff_cents += value * amount,
+            ModTarget::Pan => self.pan += value * amount / 100.0,
+        }
+    }
+}
+
+/// Envelope modulating a voice parameter in cents. The region envelope is
+/// combined with plugin params like the amp envelope: times are added as offsets,
+/// sustain is scaled and the depths add up.
+struct ModEnv {
+    env: Adsr,
+    eg: EgStages,
+    /// Region depth in cents
+    depth: f32,
+    /// Plugin params: attack, decay (ms), sustain (0..1), release (ms), depth (cents)
+    params: [f32; 5],
+}
+
+impl ModEnv {
+    fn new(sr: f32) -> Self {
+        Self {
+            env: Adsr::new(sr),
+            eg: EgStages::default(),
+            depth: 0.0,
+            params: [0.0, 0.0, 1.0, 0.0, 0.0],
+        }
+    }
+
+    fn start(&mut self, eg: EgStages, depth: f32) {
+        self.eg = eg;
+        self.depth = depth;
+        self.update();
+        self.env.reset();
+        self.env.note_on();
+    }
+
+    fn set_params(&mut self, params: [f32; 5]) {
+        if params != self.params {
+            self.params = params;
+            self.update();
+        }
+    }
+
+    fn update(&mut self) {
+        let [a_ms, d_ms, s, r_ms, _] = self.params;
+        let eg = &self.eg;
+        self.env.set_ms(
+            eg.attack * 1000.0 + a_ms.max(0.0),
+            eg.decay * 1000.0 + d_ms.max(0.0),
+            eg.sustain / 100.0 * s.max(0.0),
+            eg.release * 1000.0 + r_ms.max(0.0),
+        );
+        self.env
+            .set_delay_hold_ms(eg.delay * 1000.0, eg.hold * 1000.0);
+    }
+
+    /// Advance one sample, returning the modulation in cents
+    #[inline]
+    fn next(&mut self) -> f32 {
+        let level = self.env.next();
+        let depth = self.depth + self.params[4];
+        if depth == 0.0 { 0.0 } else { level * depth }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: f32 = 48000.0;
+
+    fn render_ms(voice: &mut Voice, instrument: &Instrument, ms: f32) {
+        for _ in 0..(ms / 1000.0 * SR) as usize {
+            voice.render(instrument, 20000.0, 0.7, FilterMode::Off, 20000.0);
+        }
+    }
+
+    #[test]
+    fn choke_fade_survives_env_param_changes() {
+        let instrument = crate::loader::create_test_instrument();
+        let mut voice = Voice::new(SR);
+        voice.set_env_ms(0.0, 0.0, 1.0, 200.0);
+        let eg = EgStages {
+            sustain: 100.0,
+            ..EgStages::default()
+        };
+        voice.start(0, 60, 1.0, 0, 1.0, eg, 0);
+        render_ms(&mut voice, &instrument, 5.0);
+
+        voice.choke(Some(6.0));
+        // A param change mid-fade must not bring back the long release
+        voice.set_env_ms(0.0, 0.0, 1.0, 5000.0);
+        render_ms(&mut voice, &instrument, 10.0);
+        assert!(!voice.active);
+    }
+
+    #[test]
+    fn noise_ignores_playback_rate() {
+        let mut instrument = crate::loader::create_test_instrument();
+        let audio = crate::loader::generate_audio("noise").unwrap();
+        instrument.regions[0].generator = audio.generator;
+        let eg = EgStages {
+            sustain: 100.0,
+            ..EgStages::default()
+        };
+        let mut low = Voice::new(SR);
+        let mut high = Voice::new(SR);
+        low.start(0, 60, 1.0, 0, 1.0, eg, 0);
+        high.start(0, 60, 1.0, 0, 64.0, eg, 0);
+        let mut energy = 0.0;
+        for _ in 0..1000 {
+            let a = low.render(&instrument, 20000.0, 0.7, FilterMode::Off, 20000.0);
+            let b = high.render(&instrument, 20000.0, 0.7, FilterMode::Off, 20000.0);
+            assert_eq!(a, b);
+            energy += a.0 * a.0;
+        }
+        assert!(energy > 0.0);
     }
 }